@@ -0,0 +1,494 @@
+use std::{fs::OpenOptions, path::Path};
+
+use anyhow::{Context, Result};
+use image::imageops::FilterType;
+use log::{info, warn};
+use rusttype::Font;
+use serde_json::{json, Value};
+
+use crate::{
+    error::ErrorKind,
+    utils::{
+        self,
+        args::{Action, Cli, OutputFormat},
+        gif::{self, process_gif},
+        image::{
+            composite_layers, fade, layers_height, load_font, load_font_file, missing_glyphs,
+            timed_caption_layers, CaptionCache, SetUp,
+        },
+        job::Job,
+        video::FFmpeg,
+        MediaType, Outcome, Tools,
+    },
+};
+
+impl Cli {
+    /// Runs the program, reporting failures to the event log.
+    ///
+    /// The log level is set from the arguments and the output is opened once it is written,
+    /// if [`Cli::opens_output()`].
+    ///
+    /// # Errors
+    /// Returns an error if the job fails.
+    pub fn run(&mut self) -> Result<()> {
+        log::set_max_level(self.log_level());
+        self.open_event_log()?;
+        let result = self.process();
+        match &result {
+            Ok(Some(outcome)) if self.json() => println!("{}", outcome_json(outcome)),
+            Err(err) => self.events().error(err),
+            _ => {}
+        }
+        if let Ok(Some(outcome)) = &result {
+            if !self.opens_output() {
+                info!("Output written to {}", outcome.output.display());
+            } else if let Err(err) = reveal(&outcome.output) {
+                warn!(
+                    "Failed to open the file manager: {err:#}\nOutput written to {}",
+                    outcome.output.display()
+                );
+            }
+        }
+        result.map(|_| ())
+    }
+
+    /// Main logic.
+    ///
+    /// Returns the outcome of the job, or [`None`] if nothing was captioned.
+    pub(crate) fn process(&mut self) -> Result<Option<Outcome>> {
+        match self.action() {
+            Some(Action::Clean) => return utils::cleanup(&self.tools()).map(|()| None),
+            Some(Action::Probe { media }) => return probe(media, &self.tools()).map(|()| None),
+            Some(Action::ListFormats) => {
+                list_formats();
+                return Ok(None);
+            }
+            Some(Action::SelfUpdate { yes }) => {
+                if self.updates_disabled() {
+                    return Err(ErrorKind::UpdatesDisabled.into());
+                }
+                return utils::update::self_update(*yes).map(|()| None);
+            }
+            None => {}
+        }
+        self.tools().check_temp_dir()?;
+        if !self.load_sidecar_caption()? {
+            let (media, _) = self.media()?;
+            warn!("No sidecar caption for {}, skipping it.", media.display());
+            return Ok(None);
+        }
+        // clap accepts a caption of only whitespace, which would fail deep inside rendering.
+        if self.caption_is_empty() {
+            return Err(ErrorKind::NoTextGiven.into());
+        }
+        let from_stdin = self.reads_stdin();
+        // an output of - is rejected before stdin is read to the end.
+        self.output()?;
+        // media from stdin is buffered for listing too, its type decides the output extension.
+        self.buffer_stdin_media()?;
+        // the job resolves the output name once, a random name is the same wherever it is used.
+        let job = self.job()?;
+        if self.list_jobs() {
+            let input = if from_stdin {
+                Path::new("-")
+            } else {
+                job.media()
+            };
+            let output = job.output().join(job.name());
+            println!("{} -> {}", input.display(), output.display());
+            if self.names_randomly() {
+                info!("Output names are random unless --output-name or --suffix is given.");
+            }
+            return Ok(None);
+        }
+        let fonts = if self.fonts().is_empty() {
+            let data = include_bytes!("../font/ifunny.otf");
+            vec![load_font(data, self.font_index(), self.font_weight())?]
+        } else {
+            self.fonts()
+                .iter()
+                .map(|path| load_font_file(path, self.font_index(), self.font_weight()))
+                .collect::<Result<Vec<_>>>()?
+        };
+        for (line, &font) in self.line_fonts().iter().enumerate() {
+            if font >= fonts.len() {
+                let count = fonts.len();
+                return Err(ErrorKind::LineFontOutOfRange { line, font, count }.into());
+            }
+        }
+        if !job.no_caption() && job.caption_image().is_none() {
+            warn_missing_glyphs(&job, &fonts);
+        }
+
+        if self.dry_run() {
+            return self.dry_run_report(&job, fonts).map(|()| None);
+        }
+
+        let mut cache = CaptionCache::new(self.font_id(), self.caption_cache_dir());
+        if self.preview() {
+            return self.show_preview(&job, fonts, &mut cache).map(|()| None);
+        }
+        utils::check_output_dir(job.output(), self.mkdir())?;
+
+        if self.dither().is_some() && self.colors().is_none() {
+            info!("Dithering only applies when colors are reduced with --colors or --reduce.");
+        }
+        if job.media_type() == MediaType::Gif && self.loop_video().is_none() {
+            if self.caption_script().is_some() {
+                return Err(ErrorKind::CaptionScriptOnGif.into());
+            }
+            if self.color_range().is_some() {
+                info!("Color ranges only work on videos.");
+            }
+            if self.output_format() == OutputFormat::Webp {
+                if self.optimizes() || self.max_size().is_some() {
+                    info!("Gifsicle cannot optimize WebP output, optimization flags are ignored.");
+                }
+                if self.comment().is_some() {
+                    info!("Comments only work on GIF output.");
+                }
+            }
+            if self.crf().is_some() || self.preset().is_some() {
+                info!("CRF and encoder presets only work on videos.");
+            }
+            if self.no_audio() || self.audio_codec().is_some() {
+                info!("Audio options only work on videos.");
+            }
+            if self.hwaccel().is_some() {
+                info!("Hardware encoding only works on videos.");
+            }
+        } else {
+            if self.optimizes() && !self.to_gif() {
+                info!("Optimization flags only work on GIFs.");
+            }
+            if self.caption_animation().is_some() {
+                info!("Caption animations only work on GIFs.");
+            }
+            if self.frame_delay().is_some() {
+                info!("Frame delays only work on GIFs.");
+            }
+            if self.speed().is_some() {
+                info!("Playback speeds only work on GIFs.");
+            }
+            if self.has_gif_quality() {
+                info!("GIF encoder quality only works on GIFs.");
+            }
+            if self.loops() != 0 {
+                info!("Loop counts only work on GIFs.");
+            }
+            if self.output_format() == OutputFormat::Webp {
+                info!("WebP output only works on GIFs.");
+            }
+            if self.comment().is_some() && !self.to_gif() {
+                info!("Comments only work on GIFs.");
+            }
+            if self.hwaccel().is_some() && self.to_gif() {
+                info!("Hardware encoding only works on video output.");
+            } else if self.hwaccel().is_some() && self.preset().is_some() {
+                info!("Encoder presets are ignored with hardware encoding.");
+            }
+            if (self.to_gif() || self.loop_video().is_some()) && self.audio_codec().is_some() {
+                info!("GIFs and looping videos are muted, the audio codec is ignored.");
+            }
+        }
+
+        caption(&job, fonts, &mut cache).map(Some)
+    }
+
+    /// Renders the caption onto the first frame of the media and opens it.
+    ///
+    /// The preview is written to the temporary directory, the media itself is not processed.
+    fn show_preview(
+        &self,
+        job: &Job,
+        fonts: Vec<Font<'static>>,
+        cache: &mut CaptionCache,
+    ) -> Result<()> {
+        let frame = if job.media_type() == MediaType::Gif {
+            gif::first_frame(job.media())?
+        } else {
+            // the frame is stored at its coded size, which may not be the displayed one.
+            let mut ffmpeg = FFmpeg::init(job.media().to_path_buf(), job.tools())?;
+            let (width, height) = ffmpeg.display_dimensions()?;
+            let frame = ffmpeg.first_frame()?;
+            image::imageops::resize(&frame, width, height, FilterType::Triangle)
+        };
+        let preview = if job.no_caption() {
+            frame
+        } else {
+            let init = SetUp::from_job(fonts, job, frame.width(), frame.height())?;
+            let segments = job.caption_segments();
+            let layers = timed_caption_layers(job, &init, cache, job.gap(), segments)?;
+            // a caption script previews its first caption.
+            let first = layers.iter().position(|(_, _, window)| window.is_some());
+            let layers: Vec<_> = layers
+                .into_iter()
+                .enumerate()
+                .filter(|(i, _)| first.map_or(true, |first| *i == first))
+                .map(|(_, (mut strip, position, _))| {
+                    fade(&mut strip, job.overlay_opacity());
+                    (strip, position)
+                })
+                .collect();
+            composite_layers(&frame, &layers, job.feather(), job.overlay_mode())
+        };
+        let path = job.tools().temp_path(".png");
+        preview
+            .save(&path)
+            .context(format!("failed to write the preview: {}", path.display()))?;
+        info!(
+            "Preview written to {}. Run again without --preview to render the media.",
+            path.display()
+        );
+        if self.opens_output() {
+            open(&path)?;
+        }
+        Ok(())
+    }
+
+    /// Prints where the output would be written and how large the caption would be.
+    ///
+    /// The caption is rendered in memory only, it is not written to the caption cache.
+    fn dry_run_report(&self, job: &Job, fonts: Vec<Font<'static>>) -> Result<()> {
+        let (width, height) = if job.media_type() == MediaType::Gif {
+            image::image_dimensions(job.media())?
+        } else {
+            FFmpeg::init(job.media().to_path_buf(), job.tools())?.display_dimensions()?
+        };
+        let caption_height = if job.no_caption() {
+            0
+        } else {
+            let init = SetUp::from_job(fonts, job, width, height)?;
+            let mut cache = CaptionCache::new(self.font_id(), None);
+            let layers =
+                timed_caption_layers(job, &init, &mut cache, job.gap(), job.caption_segments())?;
+            let (top, bottom) = layers_height(&layers);
+            top + bottom
+        };
+
+        println!("output: {}", job.output().join(job.name()).display());
+        println!("media: {width}x{height}");
+        println!("caption height: {caption_height}");
+        if self.names_randomly() {
+            info!("Output names are random unless --output-name or --suffix is given.");
+        }
+        Ok(())
+    }
+}
+
+/// Captions the media of `job` with `fonts`.
+///
+/// GIFs are captioned frame by frame, everything else and looping videos with `FFmpeg`.
+///
+/// # Errors
+/// Returns an error if the media cannot be read or captioned.
+pub(crate) fn caption(
+    job: &Job,
+    fonts: Vec<Font<'static>>,
+    cache: &mut CaptionCache,
+) -> Result<Outcome> {
+    if job.media_type() == MediaType::Gif && job.loop_video().is_none() {
+        let file = OpenOptions::new().read(true).open(job.media())?;
+        process_gif(file, fonts, job, cache)
+    } else {
+        FFmpeg::init(job.media().to_path_buf(), job.tools())?.process_media(fonts, job, cache)
+    }
+}
+
+/// Warns about characters of the caption that no font can draw, such as emoji.
+///
+/// The text renderer draws monochrome outlines only, so color emoji
+/// cannot be rendered even from a font that has them.
+fn warn_missing_glyphs(job: &Job, fonts: &[Font<'static>]) {
+    let mut text: String = [Some(job.text()), job.top_text(), job.bottom_text()]
+        .into_iter()
+        .flatten()
+        .collect::<Vec<_>>()
+        .join("\n");
+    for segment in job.caption_segments().into_iter().flatten() {
+        text.push('\n');
+        text.push_str(&segment.text);
+    }
+    let missing = missing_glyphs(fonts, &text);
+    if !missing.is_empty() {
+        let missing: Vec<_> = missing
+            .iter()
+            .map(|&c| format!("{c} (U+{:04X})", u32::from(c)))
+            .collect();
+        warn!(
+            "The font has no glyphs for {}, they will not render. Color emoji are not supported, only a --font with monochrome emoji glyphs can draw emoji.",
+            missing.join(", ")
+        );
+    }
+}
+
+/// Opens `path` with the file manager or the default program of the system.
+fn open(path: &Path) -> Result<()> {
+    #[cfg(windows)]
+    std::process::Command::new("explorer.exe")
+        .arg(path)
+        .spawn()?;
+
+    // Opening File Manager with UNIX is not tested.
+    #[cfg(unix)]
+    std::process::Command::new("xdg-open").arg(path).spawn()?;
+
+    Ok(())
+}
+
+/// Shows the written output `path` in the file manager of the system.
+///
+/// The file is selected by Explorer on Windows, by Finder on macOS and by any file manager
+/// implementing `org.freedesktop.FileManager1` elsewhere. Without one, `xdg-open` opens
+/// the folder that holds the file.
+fn reveal(path: &Path) -> Result<()> {
+    #[cfg(windows)]
+    {
+        let mut select = std::ffi::OsString::from("/select,");
+        select.push(path);
+        std::process::Command::new("explorer.exe")
+            .arg(select)
+            .spawn()?;
+    }
+
+    #[cfg(target_os = "macos")]
+    std::process::Command::new("open")
+        .arg("-R")
+        .arg(path)
+        .spawn()?;
+
+    // Opening File Manager with UNIX is not tested.
+    #[cfg(all(unix, not(target_os = "macos")))]
+    {
+        // the call fails when no file manager owns the name on the session bus.
+        let shown = std::process::Command::new("dbus-send")
+            .args([
+                "--session",
+                "--print-reply",
+                "--dest=org.freedesktop.FileManager1",
+                "--type=method_call",
+                "/org/freedesktop/FileManager1",
+                "org.freedesktop.FileManager1.ShowItems",
+            ])
+            .arg(format!("array:string:{}", file_uri(&path.canonicalize()?)))
+            .arg("string:")
+            .stdout(std::process::Stdio::null())
+            .stderr(std::process::Stdio::null())
+            .status()
+            .map_or(false, |status| status.success());
+        if !shown {
+            let dir = path
+                .parent()
+                .filter(|dir| !dir.as_os_str().is_empty())
+                .unwrap_or_else(|| Path::new("."));
+            std::process::Command::new("xdg-open").arg(dir).spawn()?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Returns the `file://` URI of the absolute `path`.
+///
+/// Every byte outside the unreserved characters and `/` is percent-encoded,
+/// which also keeps commas from splitting the `dbus-send` array.
+#[cfg(all(unix, not(target_os = "macos")))]
+fn file_uri(path: &Path) -> String {
+    use std::{fmt::Write, os::unix::ffi::OsStrExt};
+
+    let mut uri = String::from("file://");
+    for &byte in path.as_os_str().as_bytes() {
+        if byte.is_ascii_alphanumeric() || b"/-._~".contains(&byte) {
+            uri.push(char::from(byte));
+        } else {
+            let _ = write!(uri, "%{byte:02X}");
+        }
+    }
+    uri
+}
+
+/// Prints the type, dimensions and duration of `media`.
+fn probe(media: &Path, tools: &Tools) -> Result<()> {
+    let ty = utils::validate_format(media)?;
+    let ((width, height), duration) = if ty == MediaType::Gif {
+        let (dimensions, duration) = gif::probe(media)?;
+        (dimensions, Some(duration))
+    } else {
+        let mut ffmpeg = FFmpeg::init(media.to_path_buf(), tools)?;
+        (ffmpeg.display_dimensions()?, ffmpeg.duration()?)
+    };
+    println!("type: {}", ty.extension().trim_start_matches('.'));
+    println!("dimensions: {width}x{height}");
+    match duration {
+        Some(seconds) => println!("duration: {seconds:.2}s"),
+        None => println!("duration: unknown"),
+    }
+    Ok(())
+}
+
+/// Prints every supported media type and the formats it can be written as.
+fn list_formats() {
+    for ty in MediaType::ALL {
+        let name = ty.extension().trim_start_matches('.');
+        let mut outputs = vec![name.to_string()];
+        if ty == MediaType::Gif {
+            outputs.push("webp (--output-format webp)".into());
+        } else {
+            outputs.push("gif (--to-gif)".into());
+        }
+        if ty != MediaType::Mp4 {
+            outputs.push("mp4 (--loop-video)".into());
+        }
+        println!("{name} -> {}", outputs.join(", "));
+    }
+}
+
+/// Describes the outcome of a job as the JSON object printed for `--json`.
+fn outcome_json(outcome: &Outcome) -> Value {
+    let (width, height) = outcome.dimensions;
+    json!({
+        "output": outcome.output.display().to_string(),
+        "media_type": outcome.media_type.extension().trim_start_matches('.'),
+        "width": width,
+        "height": height,
+        "caption_lines": outcome.caption_lines,
+        "optimized": outcome.optimized,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::Parser;
+
+    use super::*;
+
+    #[test]
+    fn blank_caption_fails_before_media_or_fonts_are_read() {
+        for caption in ["", "   ", " \t "] {
+            // neither file exists, reading either would fail with another error.
+            let mut cli = Cli::parse_from([
+                "unlustig",
+                "-G",
+                "missing.gif",
+                "-T",
+                caption,
+                "--font",
+                "missing.ttf",
+            ]);
+            let err = cli.process().unwrap_err();
+            assert!(
+                matches!(err.downcast_ref(), Some(ErrorKind::NoTextGiven)),
+                "{caption:?}: {err}"
+            );
+        }
+    }
+
+    #[cfg(all(unix, not(target_os = "macos")))]
+    #[test]
+    fn file_uri_escapes_spaces_commas_and_unicode() {
+        assert_eq!(
+            file_uri(Path::new("/tmp/a b,c/ü.gif")),
+            "file:///tmp/a%20b%2Cc/%C3%BC.gif"
+        );
+    }
+}
@@ -16,9 +16,207 @@ pub enum ErrorKind {
     /// This error is only returned in the CLI mode
     /// as required fields are forced to be filled
     /// before any processing in the GUI.
-    #[error("no text was given")]
+    #[error("no text was given, the caption is empty (use --no-caption to skip captioning)")]
     NoTextGiven,
 
+    /// A color argument was not a valid hex color.
+    #[error("invalid color: {0} (expected #RRGGBB or #RRGGBBAA)")]
+    InvalidColor(String),
+
+    /// An optimization level was not one of `O1`, `O2` or `O3`.
+    #[error("invalid optimization level: {0} (expected O1, O2 or O3)")]
+    InvalidOptLevel(String),
+
+    /// An offset argument was not a valid `<dx>,<dy>` pair.
+    #[error("invalid offset: {0} (expected <dx>,<dy>)")]
+    InvalidOffset(String),
+
+    /// A line of a caption script is not of the form `<start> <end> <text>`.
+    #[error("invalid caption script line {line}: {reason}")]
+    InvalidCaptionScript {
+        /// Line number, starting at 1.
+        line: usize,
+        /// What is wrong with the line.
+        reason: String,
+    },
+
+    /// Two captions of a caption script are shown at the same time.
+    #[error("caption script lines {first} and {second} overlap")]
+    OverlappingCaptions {
+        /// Line of the caption that starts first.
+        first: usize,
+        /// Line of the caption that starts while the first is shown.
+        second: usize,
+    },
+
+    /// A caption script has no captions.
+    #[error("caption script has no captions: {0}")]
+    EmptyCaptionScript(std::path::PathBuf),
+
+    /// A caption script was given for a GIF.
+    #[error("caption scripts only work on videos, use --loop-video to caption a GIF as a video")]
+    CaptionScriptOnGif,
+
+    /// A color count was not a number from 2 to 256.
+    #[error("invalid color count: {0} (expected a number from 2 to 256)")]
+    InvalidColorCount(String),
+
+    /// A watermark opacity was not a number from 0 to 1.
+    #[error("invalid watermark opacity: {0} (expected a number from 0 to 1)")]
+    InvalidOpacity(String),
+
+    /// A GIF quality was not a number from 1 to 30.
+    #[error("invalid GIF quality: {0} (expected a number from 1 to 30)")]
+    InvalidGifQuality(String),
+
+    /// A playback speed was not a positive number.
+    #[error("invalid speed: {0} (expected a positive number, e.g. 2.0 to play twice as fast)")]
+    InvalidSpeed(String),
+
+    /// A font weight was not a number from 1 to 1000.
+    #[error("invalid font weight: {0} (expected a number from 1 to 1000, e.g. 700 for bold)")]
+    InvalidFontWeight(String),
+
+    /// A CRF argument was not a number from 0 to 51.
+    #[error("invalid CRF: {0} (expected a number from 0 to 51)")]
+    InvalidCrf(String),
+
+    /// The requested face does not exist in the font.
+    #[error("font face index {index} is out of range, the font has {count} face(s)")]
+    FontIndexOutOfRange {
+        /// Requested face index.
+        index: u32,
+        /// Number of faces in the font.
+        count: u32,
+    },
+
+    /// A font file is not a valid font.
+    #[error("failed to load font: {} is not a valid TTF/OTF font", .0.display())]
+    FontLoad(std::path::PathBuf),
+
+    /// A caption was set up without any font to render it with.
+    #[error("no font was given to render the caption with")]
+    NoFonts,
+
+    /// A wrapped line was mapped to a font that was not given.
+    #[error("line {line} uses font {font}, but only {count} font(s) were given")]
+    LineFontOutOfRange {
+        /// Line that was mapped.
+        line: usize,
+        /// Requested font index.
+        font: usize,
+        /// Number of fonts given.
+        count: usize,
+    },
+
+    /// FFmpeg exited with an error.
+    #[error("ffmpeg exited with {status}: {stderr}")]
+    FfmpegFailed {
+        /// Exit status of ffmpeg.
+        status: std::process::ExitStatus,
+        /// The end of what ffmpeg printed to stderr.
+        stderr: String,
+    },
+
+    /// An external process ran for longer than `--timeout` and was stopped.
+    #[error("{program} did not finish within {seconds}s and was stopped (raise --timeout to give it more time)")]
+    ProcessTimedOut {
+        /// The program that was run.
+        program: String,
+        /// The timeout in seconds.
+        seconds: u64,
+    },
+
+    /// `self-update` was run with updates disabled.
+    #[error("updates are disabled by --no-update-check or UNLUSTIG_NO_UPDATE")]
+    UpdatesDisabled,
+
+    /// The newest release has no executable for this platform.
+    #[error("release {0} has no executable for this platform, download it from https://github.com/bltzxsd/unlustig/releases/latest")]
+    NoReleaseExecutable(String),
+
+    /// The downloaded release is not an executable for this platform.
+    #[error("the downloaded release is not an executable for this platform: {0}")]
+    InvalidReleaseExecutable(std::path::PathBuf),
+
+    /// Gifsicle exited with an error.
+    #[error("gifsicle exited with {status}: {stderr}")]
+    GifsicleFailed {
+        /// Exit status of gifsicle.
+        status: std::process::ExitStatus,
+        /// The end of what gifsicle printed to stderr.
+        stderr: String,
+    },
+
+    /// FFmpeg finished without writing the output file.
+    #[error("ffmpeg did not write any output to {path}: {stderr}")]
+    NoOutputWritten {
+        /// Expected output file.
+        path: String,
+        /// What ffmpeg printed to stderr.
+        stderr: String,
+    },
+
+    /// Temporary files cannot be written.
+    #[error(
+        "temporary directory is not writable: {} (use --temp-dir to choose another directory)",
+        .0.display()
+    )]
+    TempDirUnwritable(std::path::PathBuf),
+
+    /// The output directory does not exist.
+    #[error(
+        "output directory does not exist: {} (use --output-directory to choose another directory, or --mkdir to create it)",
+        .0.display()
+    )]
+    OutputDirMissing(std::path::PathBuf),
+
+    /// Files cannot be written to the output directory.
+    #[error(
+        "output directory is not writable: {} (use --output-directory to choose another directory)",
+        .0.display()
+    )]
+    OutputDirUnwritable(std::path::PathBuf),
+
+    /// The output was given as `-`, but the output cannot be written to stdout.
+    #[error("the output cannot be written to stdout, - is not a valid output directory or file (choose a path to write to)")]
+    OutputToStdout,
+
+    /// A dependency path given with a flag or environment variable is not an executable.
+    #[error("{dep} path is not an executable file: {}", .path.display())]
+    NotAnExecutable {
+        /// The dependency the path was given for.
+        dep: String,
+        /// The given path.
+        path: std::path::PathBuf,
+    },
+
+    /// The extension of the output file does not fit the output media.
+    #[error("output file {} does not fit the output, expected a {expected} file", .path.display())]
+    OutputExtensionMismatch {
+        /// The given output file.
+        path: std::path::PathBuf,
+        /// Extension of the output media, including the leading dot.
+        expected: String,
+    },
+
+    /// The media has no usable dimensions, it is most likely corrupt.
+    #[error("could not read media dimensions of {}: {width}x{height} (expected at least {min}x{min} pixels)", .path.display())]
+    InvalidDimensions {
+        /// Path of the media.
+        path: std::path::PathBuf,
+        /// Detected width.
+        width: u32,
+        /// Detected height.
+        height: u32,
+        /// Smallest width and height that can be captioned.
+        min: u32,
+    },
+
+    /// The output would be taller than encoders support.
+    #[error("output would be {0}px tall, which exceeds the maximum of 65535px")]
+    OutputTooLarge(u32),
+
     /// Gifsicle was not found.
     #[error(
         "gifsicle not found, if using Unix, please install Gifsicle using your pkg manager: {0}"
@@ -0,0 +1,139 @@
+#![warn(clippy::double_comparisons, clippy::missing_errors_doc)]
+#![deny(missing_docs)]
+
+//! Unlustig-rs
+//!
+//! iFunny Gif Caption Maker.
+//!
+//! Captions GIFs and videos with [`caption_gif()`], or with [`process_gif()`] and
+//! [`FFmpeg::process_media()`] and a [`Job`] for full control over the options.
+
+use std::path::{Path, PathBuf};
+
+use anyhow::Result;
+use rusttype::Font;
+
+use error::ErrorKind;
+use utils::{
+    args::font_id,
+    image::{load_font, load_font_file, CaptionCache},
+};
+pub use utils::{
+    args::{CaptionPosition, Cli},
+    gif::process_gif,
+    image::{SetUp, TextImage},
+    job::Job,
+    video::FFmpeg,
+    Outcome, Tools,
+};
+
+/// Runs jobs described by the [`Cli`].
+mod app;
+
+/// Error module.
+pub mod error;
+
+/// Utility module.
+pub mod utils;
+
+/// Options for [`caption_gif()`].
+///
+/// Anything not set uses the defaults of the command line.
+#[derive(Debug, Clone, Default)]
+pub struct CaptionOptions {
+    output_directory: Option<PathBuf>,
+    output_name: Option<String>,
+    overwrite: bool,
+    font: Option<PathBuf>,
+    position: Option<CaptionPosition>,
+    optimization: Option<String>,
+}
+
+impl CaptionOptions {
+    /// Creates options with the defaults of the command line.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets the directory the output is written to.
+    pub fn with_output_directory(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.output_directory = Some(dir.into());
+        self
+    }
+
+    /// Sets the name of the output file, without extension.
+    pub fn with_output_name(mut self, name: impl Into<String>) -> Self {
+        self.output_name = Some(name.into());
+        self
+    }
+
+    /// Sets whether an existing output file is overwritten.
+    pub fn with_overwrite(mut self, overwrite: bool) -> Self {
+        self.overwrite = overwrite;
+        self
+    }
+
+    /// Sets the font the caption is rendered with.
+    pub fn with_font(mut self, font: impl Into<PathBuf>) -> Self {
+        self.font = Some(font.into());
+        self
+    }
+
+    /// Sets the side of the media the caption is placed on.
+    pub fn with_position(mut self, position: CaptionPosition) -> Self {
+        self.position = Some(position);
+        self
+    }
+
+    /// Sets the Gifsicle optimization level, e.g. `O2`.
+    pub fn with_optimization(mut self, level: impl Into<String>) -> Self {
+        self.optimization = Some(level.into());
+        self
+    }
+
+    /// Returns the job captioning `media` with `text`.
+    fn job(&self, media: &Path, text: &str) -> Result<Job> {
+        let mut job = Job::new(media, text)?.with_overwrite(self.overwrite);
+        if let Some(dir) = &self.output_directory {
+            job = job.with_output(dir);
+        }
+        if let Some(name) = &self.output_name {
+            job = job.with_name(name);
+        }
+        if let Some(position) = self.position {
+            job = job.with_position(position);
+        }
+        if let Some(level) = &self.optimization {
+            job = job.with_opt_level(level)?;
+        }
+        Ok(job)
+    }
+
+    /// Returns the font the caption is rendered with.
+    fn load_font(&self) -> Result<Font<'static>> {
+        match &self.font {
+            Some(path) => load_font_file(path, 0, None),
+            None => load_font(include_bytes!("../font/ifunny.otf"), 0, None),
+        }
+    }
+}
+
+/// Captions the GIF or video at `path` with `text`.
+///
+/// Returns the path the captioned media was written to.
+///
+/// # Errors
+/// Returns an error if the options are invalid or the media cannot be captioned.
+pub fn caption_gif(path: &Path, text: &str, opts: &CaptionOptions) -> Result<PathBuf> {
+    let job = opts.job(path, text)?;
+    if job.text().is_empty() {
+        return Err(ErrorKind::NoTextGiven.into());
+    }
+    let font = opts.load_font()?;
+    job.tools().check_temp_dir()?;
+    utils::check_output_dir(job.output(), false)?;
+    let fonts: Vec<_> = opts.font.iter().cloned().collect();
+    let mut cache = CaptionCache::new(font_id(&fonts, 0, None), None);
+    let outcome = app::caption(&job, vec![font], &mut cache)?;
+    Ok(outcome.output)
+}
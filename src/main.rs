@@ -6,40 +6,44 @@
 //!
 //! iFunny Gif Caption Maker.
 
-use std::fs::OpenOptions;
+use std::time::Duration;
 
-use anyhow::{Context, Result};
+use anyhow::Result;
 
 use klask::Settings;
-use log::{debug, error, info, trace, warn};
+use log::{debug, error, trace, warn, LevelFilter};
 
 use rich_presence::Discord;
-use rusttype::Font;
-use semver::Version;
-use serde_json::Value;
-use utils::{args::Cli, gif::process_gif, video::FFmpeg, MediaType};
+use unlustig::utils::{
+    args::{log_level, Cli},
+    update,
+};
 use yansi::Paint;
 
-/// Error module.
-pub mod error;
-
 /// Rich Presence module.
 mod rich_presence;
 
-/// Utility module.
-pub mod utils;
-
 fn main() {
+    // the logger lets every level through, the level is limited by the arguments.
     simple_logger::SimpleLogger::new()
-        .with_level(log::LevelFilter::Info)
+        .with_level(LevelFilter::Trace)
         .init()
         .expect("failed to start logger");
+    log::set_max_level(raw_log_level());
 
-    if let Err(e) = check_updates() {
+    if raw_flag("--no-update-check", update::NO_UPDATE_VAR) {
+        debug!("update check is disabled");
+    } else if let Err(e) = check_updates() {
         debug!("Failed to check for updates: {e}")
     }
-    if let Err(e) = Discord::init("930897511743356950") {
-        debug!("failed discord RPC initialization: {e}");
+    // the connection is closed when the handle is dropped at the end of main.
+    let _discord = if raw_flag("--no-rpc", "UNLUSTIG_NO_RPC") {
+        debug!("discord RPC is disabled");
+        None
+    } else {
+        Discord::init("930897511743356950")
+            .map_err(|e| debug!("failed discord RPC initialization: {e}"))
+            .ok()
     };
 
     let custom_font = include_bytes!("../font/mononoki-Regular.ttf");
@@ -56,7 +60,7 @@ fn main() {
                 ..Settings::default()
             };
 
-            klask::run_derived::<Cli, _>(settings, |cli| {
+            klask::run_derived::<Cli, _>(settings, |mut cli| {
                 if let Err(err) = cli.run() {
                     error!("{:?}", err);
                 }
@@ -71,7 +75,7 @@ fn main() {
             ..Settings::default()
         };
 
-        klask::run_derived::<Cli, _>(settings, |cli| {
+        klask::run_derived::<Cli, _>(settings, |mut cli| {
             if let Err(err) = cli.run() {
                 error!("{:?}", err);
             }
@@ -79,56 +83,46 @@ fn main() {
     }
 }
 
-impl Cli {
-    /// Main logic.
-    fn run(&self) -> Result<()> {
-        let font = Font::try_from_bytes(include_bytes!("../font/ifunny.otf"))
-            .context("failed to read font")?;
-
-        let (text, out_path, name, overwrite) =
-            (self.text(), self.output()?, self.name()?, self.overwrites());
-
-        if let Ok((file_path, file_ty)) = self.media() {
-            let file = OpenOptions::new().read(true).open(&file_path)?;
-            if let MediaType::Gif = file_ty {
-                process_gif(file, font, self)?
-            } else {
-                if self.reduce() || self.lossy().is_some() || self.opt_level().is_some() {
-                    info!("Optimization flags only work on GIFs.");
-                }
-                FFmpeg::init(file_path)?.process_media(font, text, &out_path, &name, overwrite)?;
+/// Reads the log level from the raw arguments.
+///
+/// The update check and the Discord connection log before the arguments are parsed.
+fn raw_log_level() -> LevelFilter {
+    let args: Vec<_> = std::env::args().skip(1).collect();
+    let quiet = args
+        .iter()
+        .any(|arg| arg == "-q" || arg == "--quiet" || arg == "--json");
+    let verbose = args
+        .iter()
+        .map(|arg| match arg.as_str() {
+            "--verbose" => 1,
+            short if short.starts_with('-') && short[1..].chars().all(|c| c == 'v') => {
+                short.len() as u64 - 1
             }
-        }
-
-        #[cfg(windows)]
-        std::process::Command::new("explorer.exe")
-            .arg(out_path)
-            .spawn()?;
-
-        // Opening File Manager with UNIX is not tested.
-        #[cfg(unix)]
-        std::process::Command::new("xdg-open")
-            .arg(out_path)
-            .spawn()?;
+            _ => 0,
+        })
+        .sum();
+    log_level(verbose, quiet)
+}
 
-        Ok(())
-    }
+/// Checks if a startup `flag` was passed or the environment variable `env` is set.
+///
+/// The update check and the Discord connection run before the arguments are parsed,
+/// so their flags are looked up in the raw arguments.
+fn raw_flag(flag: &str, env: &str) -> bool {
+    std::env::var_os(env).is_some() || std::env::args().any(|arg| arg == flag)
 }
 
+/// How long the update check may take before it is given up on.
+const UPDATE_CHECK_TIMEOUT_SECS: u64 = 3;
+
 fn check_updates() -> Result<()> {
-    let url = "https://api.github.com/repos/bltzxsd/unlustig/releases";
-    let request = ureq::get(url).call()?;
-    let data = request.into_string()?;
-    let git_tag = serde_json::from_str::<Value>(&data)?[0]["tag_name"]
-        .to_string()
-        .replace(&['\"', 'v'][..], "");
-    let (curr_ver, web_ver): (Version, Version) =
-        (clap::crate_version!().parse()?, git_tag.parse()?);
+    let release = update::latest_release(Duration::from_secs(UPDATE_CHECK_TIMEOUT_SECS))?;
+    let (curr_ver, web_ver) = (update::current_version()?, release.version);
 
     match curr_ver.cmp(&web_ver) {
         std::cmp::Ordering::Greater => debug!(":face_with_raised_eyebrow:"),
         std::cmp::Ordering::Less => warn!(
-            "{}\nUpdate here: https://github.com/bltzxsd/unlustig/releases/latest",
+            "{}\nUpdate with `unlustig self-update` or here: https://github.com/bltzxsd/unlustig/releases/latest",
             Paint::red("unlustig is out of date!").bold(),
         ),
         std::cmp::Ordering::Equal => trace!("unlustig is up to date"),
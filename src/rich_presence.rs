@@ -10,6 +10,22 @@ use discord_rich_presence::{
 use log::debug;
 use std::fmt::Debug;
 
+/// The part of a Discord RPC client that is still used once the activity is set.
+///
+/// Implemented for every [`DiscordIpc`] client, it lets tests stand in for Discord.
+///
+/// [`DiscordIpc`]: discord_rich_presence::DiscordIpc
+trait Connection: Debug + Send + Sync {
+    /// Closes the RPC connection.
+    fn close(&mut self) -> Result<()>;
+}
+
+impl<T: DiscordIpc + Send + Sync> Connection for T {
+    fn close(&mut self) -> Result<()> {
+        DiscordIpc::close(self)
+    }
+}
+
 /// A Discord RPC client.
 ///
 /// A handle to RPC connection using a trait-object that implements the [`DiscordIpc`] trait.
@@ -17,7 +33,7 @@ use std::fmt::Debug;
 /// [`DiscordIpc`]: discord_rich_presence::DiscordIpc
 #[derive(Debug)]
 pub struct Discord {
-    inner: Box<dyn DiscordIpc + Send + Sync>,
+    inner: Box<dyn Connection>,
 }
 
 impl Discord {
@@ -62,6 +78,44 @@ impl Discord {
 impl Drop for Discord {
     /// Closes the Discord RPC connection.
     fn drop(&mut self) {
-        self.inner.close().expect("could not close discord");
+        // the connection may already be gone at exit, panicking here would abort the program.
+        if let Err(e) = self.inner.close() {
+            debug!("could not close discord: {e}");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    };
+
+    use anyhow::anyhow;
+
+    use super::*;
+
+    /// A connection that Discord has already dropped.
+    #[derive(Debug, Default)]
+    struct Gone {
+        closed: Arc<AtomicBool>,
+    }
+
+    impl Connection for Gone {
+        fn close(&mut self) -> Result<()> {
+            self.closed.store(true, Ordering::SeqCst);
+            Err(anyhow!("broken pipe"))
+        }
+    }
+
+    #[test]
+    fn drop_survives_a_failing_close() {
+        let gone = Gone::default();
+        let closed = Arc::clone(&gone.closed);
+        drop(Discord {
+            inner: Box::new(gone),
+        });
+        assert!(closed.load(Ordering::SeqCst));
     }
 }
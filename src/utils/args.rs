@@ -1,12 +1,37 @@
-use crate::utils::{random_name, validate_format, MediaType};
-use anyhow::Result;
-use clap::{Parser, ValueHint};
-use std::{io, path::PathBuf};
+use crate::{
+    error::ErrorKind,
+    utils::{
+        events::EventLog,
+        image::{Shadow, Stroke},
+        job::Job,
+        random_output_name, sniff_format, update, validate_format, MediaType, TempFile, Tools,
+    },
+};
+use anyhow::{Context, Result};
+use clap::{ArgEnum, Parser, Subcommand, ValueHint};
+use image::Rgba;
+use log::{info, LevelFilter};
+use rustc_hash::FxHasher;
+use std::{
+    cmp::Ordering,
+    fs,
+    hash::{Hash, Hasher},
+    io::{self, Read},
+    path::{Path, PathBuf},
+    sync::Once,
+    time::Duration,
+};
 
 /// CLI arguments parser for GUI and TUI.
 #[derive(Parser, Debug)]
-#[clap(author, version, about)]
+#[clap(author, version, about, subcommand_negates_reqs = true)]
 pub struct Cli {
+    /// Action to run instead of captioning.
+    ///
+    /// See also: [`Cli::action()`]
+    #[clap(subcommand)]
+    action: Option<Action>,
+
     /// Caption for the image.
     ///
     /// See also: [`Cli::text()`]   
@@ -16,9 +41,97 @@ pub struct Cli {
         value_name = "Text",
         help = "Your caption goes here.",
         long_help = None,
-        required = true,
+        required_unless_present_any = &["no-caption", "caption-image", "caption-from-sidecar", "top-text", "bottom-text", "caption-script"],
+    )]
+    caption: Option<String>,
+
+    /// Pre-rendered caption image used instead of rendering the caption text.
+    ///
+    /// See also: [`Cli::caption_image()`]
+    #[clap(
+        long,
+        parse(from_os_str),
+        value_name = "Image",
+        value_hint = ValueHint::FilePath,
+        help = "Use an image as the caption instead of rendering text. It is scaled to the media's width.",
+        long_help = None,
+        conflicts_with_all = &["caption", "caption-animate"],
+    )]
+    caption_image: Option<PathBuf>,
+
+    /// Caption placed above the media in the top and bottom caption mode.
+    ///
+    /// See also: [`Cli::top_text()`]
+    #[clap(
+        long,
+        value_name = "Text",
+        help = "Caption above the media, for a classic top and bottom text meme.",
+        long_help = "Caption above the media, for a classic top and bottom text meme. Used instead of --caption, and --position is ignored. Can be given without --bottom-text.",
+        conflicts_with_all = &["caption", "caption-image", "caption-animate", "caption-from-sidecar", "no-caption"],
+    )]
+    top_text: Option<String>,
+
+    /// Caption placed below the media in the top and bottom caption mode.
+    ///
+    /// See also: [`Cli::bottom_text()`]
+    #[clap(
+        long,
+        value_name = "Text",
+        help = "Caption below the media, for a classic top and bottom text meme.",
+        long_help = "Caption below the media, for a classic top and bottom text meme. Used instead of --caption, and --position is ignored. Can be given without --top-text.",
+        conflicts_with_all = &["caption", "caption-image", "caption-animate", "caption-from-sidecar", "no-caption"],
+    )]
+    bottom_text: Option<String>,
+
+    /// Script of captions shown at different times of a video.
+    ///
+    /// See also: [`Cli::caption_segments()`]
+    #[clap(
+        long,
+        parse(from_os_str),
+        value_name = "Script",
+        value_hint = ValueHint::FilePath,
+        help = "Show timed captions from a file of `<start> <end> <text>` lines, in seconds. Only works on videos.",
+        long_help = "Show timed captions from a file of `<start> <end> <text>` lines, e.g. `0 2.5 hello`. Times are in seconds, captions may not overlap, and the caption strip is blank between them. Empty lines and lines starting with # are skipped. Only works on videos, use --loop-video to caption a GIF this way.",
+        conflicts_with_all = &["caption", "caption-image", "caption-animate", "caption-from-sidecar", "no-caption", "top-text", "bottom-text"],
+    )]
+    caption_script: Option<PathBuf>,
+
+    /// Determines whether the caption is read from a text file next to the media.
+    ///
+    /// See also: [`Cli::load_sidecar_caption()`]
+    #[clap(
+        long,
+        help = "Read the caption from the .txt file with the same name as the media, e.g. cat.txt for cat.gif.",
+        long_help = "Read the caption from the .txt file with the same name as the media, e.g. cat.txt for cat.gif. Every line of the file becomes a line of the caption. It overrides --caption.",
+        conflicts_with_all = &["no-caption", "caption-image"],
+    )]
+    caption_from_sidecar: bool,
+
+    /// What to do when the media has no sidecar caption.
+    ///
+    /// See also: [`Cli::load_sidecar_caption()`]
+    #[clap(
+        long,
+        arg_enum,
+        value_name = "Policy",
+        help = "What to do when the media has no sidecar caption.",
+        long_help = "What to do when the media has no sidecar caption. Caption falls back to --caption, skip leaves the media unprocessed.",
+        default_value = "caption",
+        requires = "caption-from-sidecar"
+    )]
+    missing_sidecar: MissingSidecar,
+
+    /// Determines whether the media should be passed through without a caption.
+    ///
+    /// See also: [`Cli::no_caption()`]
+    #[clap(
+        long,
+        help = "Skip captioning and only optimize or transcode the media.",
+        long_help = None,
+        conflicts_with_all = &["caption", "caption-image"],
     )]
-    caption: String,
+    no_caption: bool,
 
     /// Input media for processing.
     ///
@@ -26,7 +139,7 @@ pub struct Cli {
     #[clap(
         short = 'G',
         long,
-        help = "Path to the media file.",
+        help = "Path to the media file. Use - to read the media from stdin.",
         parse(from_os_str),
         value_name = "Media: .mp4 / .gif etc.",
         value_hint = ValueHint::FilePath,
@@ -61,6 +174,43 @@ pub struct Cli {
     )]
     output_name: Option<String>,
 
+    /// Suffix appended to the stem of the output name.
+    ///
+    /// See also: [`Cli::name()`]
+    #[clap(
+        long,
+        value_name = "Suffix",
+        min_values = 0,
+        default_missing_value = "_captioned",
+        help = "Name the output after the input with this suffix, e.g. cat_captioned.gif. Defaults to _captioned.",
+        long_help = "Name the output after the input with this suffix before the extension, e.g. cat.gif is written to cat_captioned.gif. Defaults to _captioned when given without a value. With --output-name, the suffix is appended to that name instead. An existing file is overwritten only with --force-overwrite.",
+        conflicts_with = "output-file"
+    )]
+    suffix: Option<String>,
+
+    /// Full path of the output file.
+    ///
+    /// See also: [`Cli::output()`], [`Cli::name()`]
+    #[clap(
+        long,
+        value_name = "Path",
+        help = "Set the full path of the output file. Takes precedence over --output-directory and --output-name.",
+        long_help = "Set the full path of the output file. Takes precedence over --output-directory and --output-name. GIF output must keep the extension of its format, video output may use any supported video extension.",
+        parse(from_os_str),
+        value_hint = ValueHint::FilePath
+    )]
+    output_file: Option<PathBuf>,
+
+    /// Determines whether a missing output directory is created.
+    ///
+    /// See also: [`Cli::mkdir()`]
+    #[clap(
+        long,
+        help = "Create the output directory if it does not exist.",
+        long_help = None,
+    )]
+    mkdir: bool,
+
     /// Determines if the output should overwrite
     /// a pre-existing file.
     ///
@@ -82,9 +232,10 @@ pub struct Cli {
         short = 'z',
         long,
         value_name = "Level",
-        help = "Optimize the output GIF.",
-        long_help = "Optimize the output GIF. Note: Compression and processing time increases with higher values. Powered by Gifsicle",
-        possible_values = ["O1", "O2", "O3"],
+        help = "Optimize the output GIF. One of O1, O2 or O3.",
+        long_help = "Optimize the output GIF. One of O1, O2 or O3, in any case and with or without a leading '-'. Note: Compression and processing time increases with higher values. Powered by Gifsicle",
+        allow_hyphen_values = true,
+        parse(try_from_str = parse_opt_level),
     )]
     optimization: Option<String>,
 
@@ -106,114 +257,2298 @@ pub struct Cli {
 
     /// Determines whether the output should have its colors reduced to 256.
     /// Corresponds to the `--color reduce 256` argument in [Gifsicle](https://www.lcdf.org/gifsicle/).
+    /// Kept as an alias of `--colors 256`.
     ///
     /// Reduce is implemeted only for [`Gif`]s.
     ///
+    /// See also: [`Cli::colors()`]
+    ///
     /// [`Gif`]: crate::utils::MediaType::Gif
     #[clap(
         short = 'r',
         long,
         help = "Reduce the number of distinct colors in each output GIF. Powered by Gifsicle",
-        long_help = None,
+        long_help = "Reduce the number of distinct colors in each output GIF to 256. The same as --colors 256. Powered by Gifsicle",
+        conflicts_with = "colors"
     )]
     reduce: bool,
-}
 
-impl Cli {
-    /// Returns the lossiness level.
+    /// Number of distinct colors the output GIF is reduced to.
     ///
-    /// # Option
-    /// Returns `None` if no lossiness was given.
-    pub fn lossy(&self) -> Option<u32> {
-        self.lossy
-    }
+    /// See also: [`Cli::colors()`]
+    #[clap(
+        long,
+        value_name = "Count",
+        help = "Reduce the output GIF to this many distinct colors, from 2 to 256. Powered by Gifsicle",
+        long_help = "Reduce the output GIF to this many distinct colors, from 2 to 256. Fewer colors make smaller files, but gradients band. Powered by Gifsicle",
+        parse(try_from_str = parse_colors),
+    )]
+    colors: Option<u16>,
 
-    /// Returns a tuple of the input media's [`Path`] and [`Type`]
+    /// Dithering used when the colors of the output GIF are reduced.
     ///
-    /// # Errors
-    /// Returns an [`UnsupportedMediaFormat`] error if
-    /// the input file is unsupported.
+    /// See also: [`Cli::dither()`]
+    #[clap(
+        long,
+        arg_enum,
+        value_name = "Method",
+        help = "Dither the output GIF when its colors are reduced, to hide banding on gradients. Powered by Gifsicle",
+        long_help = "Dither the output GIF when its colors are reduced, to hide banding on gradients. Only applies with --colors or --reduce. Powered by Gifsicle"
+    )]
+    dither: Option<Dither>,
+
+    /// Maximum size of the output file in bytes.
     ///
-    /// [`UnsupportedMediaFormat`]: crate::error::ErrorKind::UnsupportedMediaFormat
-    /// [`Path`]: std::path::Path
-    /// [`Type`]: crate::utils::MediaType
-    pub fn media(&self) -> Result<(PathBuf, MediaType)> {
-        Ok((self.media.clone(), validate_format(&self.media)?))
-    }
+    /// See also: [`Cli::max_size()`]
+    #[clap(
+        long,
+        value_name = "Bytes",
+        help = "Shrink the output until it is at most this many bytes.",
+        long_help = "Shrink the output until it is at most this many bytes. GIFs are re-optimized with Gifsicle using increasingly lossy settings, videos are re-encoded with increasing CRF values."
+    )]
+    max_size: Option<u64>,
 
-    /// Returns the name of the output media.
+    /// Determines whether a copy of the GIF from before optimization is kept.
     ///
-    /// # Errors
-    /// Returns an [`UnsupportedMediaFormat`] error if
-    /// the input file is unsupported.
+    /// See also: [`Cli::keep_unoptimized()`]
+    #[clap(
+        long,
+        help = "Also keep the GIF from before optimization, to compare it with the optimized one.",
+        long_help = None,
+    )]
+    keep_unoptimized: bool,
+
+    /// Determines whether GIFs that fail to decode are repaired with Gifsicle.
     ///
-    /// [`UnsupportedMediaFormat`]: crate::error::ErrorKind::UnsupportedMediaFormat
-    pub fn name(&self) -> Result<String> {
-        let (_, ty) = self.media()?;
-        let ext = match ty {
-            MediaType::Mp4 => ".mp4",
-            MediaType::Avi => ".avi",
-            MediaType::Mkv => ".mkv",
-            MediaType::Webm => ".webm",
-            MediaType::Gif => ".gif",
-            MediaType::Mov => ".mov",
-        };
-        match &self.output_name {
-            Some(string) => {
-                if !string.contains(ext) {
-                    return Ok(format!("{}{}", string, ext));
-                }
-                Ok(string.to_owned())
-            }
-            None => Ok(format!("{}{}", random_name(), ext)),
-        }
-    }
+    /// See also: [`Cli::repair_gif()`]
+    #[clap(
+        long,
+        help = "Rewrite GIFs that fail to decode with Gifsicle and try again. Powered by Gifsicle",
+        long_help = None,
+    )]
+    repair_gif: bool,
 
-    /// Returns the Optimization level of output.
+    /// Comment embedded in the output GIF.
     ///
-    /// # Option
-    /// Returns [`None`] if no optimization level was specified.
-    pub fn opt_level(&self) -> Option<&str> {
-        self.optimization.as_deref()
-    }
+    /// See also: [`Cli::comment()`]
+    #[clap(
+        long,
+        value_name = "Text",
+        help = "Embed a comment in the output GIF. Without a value, a comment naming unlustig is used.",
+        long_help = None,
+        min_values = 0,
+        default_missing_value = concat!("made with unlustig v", env!("CARGO_PKG_VERSION")),
+    )]
+    comment: Option<String>,
 
-    /// Returns the directory where the output should be saved.
+    /// Number of times the media is played in a looping video output.
     ///
-    /// If the output directory was not specified, either of
-    /// the following directories will be returned:
+    /// See also: [`Cli::loop_video()`]
+    #[clap(
+        long,
+        value_name = "Times",
+        help = "Output a muted, looping mp4 (gifv) that plays the media this many times.",
+        long_help = "Output a muted, looping mp4 (gifv) that plays the media this many times. Works with GIFs and videos, the output is always an mp4 that starts playing before it is fully loaded."
+    )]
+    loop_video: Option<u32>,
+
+    /// Determines whether a video is output as a GIF.
     ///
-    /// - On Unix: `/home/<username>/Pictures`
-    /// - On Windows: `<drive>:\Users\<username>\Pictures`
+    /// See also: [`Cli::to_gif()`]
+    #[clap(
+        long,
+        help = "Output a GIF instead of a video.",
+        long_help = "Output a GIF instead of a video. The palette is generated from the captioned video, and the GIF can be optimized like any other. Only works on videos.",
+        conflicts_with = "loop-video"
+    )]
+    to_gif: bool,
+
+    /// Width the captioned output is scaled to.
     ///
-    /// # Errors
-    /// Returns an [`NotFound`] error if the default pictures folder was not found.
+    /// See also: [`Cli::scale_output()`]
+    #[clap(
+        long,
+        value_name = "Width",
+        help = "Scale the captioned output to this width, keeping its aspect ratio.",
+        long_help = "Scale the captioned output to this width, keeping its aspect ratio. Odd widths are rounded down and the height is rounded to an even number, as video encoders need even dimensions."
+    )]
+    scale_output: Option<u32>,
+
+    /// Number of threads `FFmpeg` encodes with.
     ///
-    /// [`NotFound`]: std::io::ErrorKind::NotFound  
-    pub fn output(&self) -> Result<PathBuf> {
-        match &self.output_directory {
-            Some(output) => Ok(output.clone()),
-            None => match dirs::picture_dir() {
-                Some(path) => Ok(path),
-                None => Err(
-                    anyhow::Error::from(io::Error::from(io::ErrorKind::NotFound))
-                        .context("default pictures folder was not found"),
-                ),
-            },
-        }
-    }
+    /// See also: [`Cli::encoder_threads()`]
+    #[clap(
+        long,
+        value_name = "Threads",
+        help = "Limit the number of threads FFmpeg encodes videos with.",
+        long_help = "Limit the number of threads FFmpeg encodes videos with. Defaults to FFmpeg's choice, which uses every core. Gifsicle is single-threaded, so this does not affect GIF optimization."
+    )]
+    encoder_threads: Option<u32>,
 
-    /// Returns true if force overwrite is enabled.
-    pub fn overwrites(&self) -> bool {
-        self.force_overwrite
-    }
+    /// Color range of the output video.
+    ///
+    /// See also: [`Cli::color_range()`]
+    #[clap(
+        long,
+        arg_enum,
+        value_name = "Range",
+        help = "Color range of the output video. Defaults to the range of the input.",
+        long_help = "Color range of the output video: tv (limited) or pc (full). Defaults to the range of the input, which fixes captioned videos looking washed out or crushed. Only works on videos."
+    )]
+    color_range: Option<ColorRange>,
 
-    /// Returns true if `--colors 256` is enabled.
-    pub fn reduce(&self) -> bool {
-        self.reduce
-    }
+    /// Constant rate factor of the output video.
+    ///
+    /// See also: [`Cli::crf()`]
+    #[clap(
+        long,
+        value_name = "N",
+        help = "Encode the video with this CRF, from 0 (lossless) to 51 (worst).",
+        long_help = "Encode the video with this constant rate factor, from 0 (lossless) to 51 (worst). Lower values look better and make larger files. Defaults to FFmpeg's choice. Only works on videos.",
+        parse(try_from_str = parse_crf),
+    )]
+    crf: Option<u32>,
 
-    /// Returns the caption text with whitespace trimmed.
-    pub fn text(&self) -> &str {
-        self.caption.trim()
+    /// Encoder preset of the output video.
+    ///
+    /// See also: [`Cli::preset()`]
+    #[clap(
+        long,
+        arg_enum,
+        value_name = "Preset",
+        help = "Encoder preset of the output video. Slower presets make smaller files.",
+        long_help = "Encoder preset of the output video. Slower presets make smaller files at the same quality. Defaults to FFmpeg's choice (medium). Only works on videos."
+    )]
+    preset: Option<Preset>,
+
+    /// Determines whether the audio of a video is removed.
+    ///
+    /// See also: [`Cli::no_audio()`]
+    #[clap(
+        long,
+        help = "Remove the audio from the output video.",
+        long_help = None,
+        conflicts_with = "audio-codec"
+    )]
+    no_audio: bool,
+
+    /// Codec the audio of the output video is re-encoded with.
+    ///
+    /// See also: [`Cli::audio_codec()`]
+    #[clap(
+        long,
+        value_name = "Codec",
+        help = "Re-encode the audio of the output video with this codec, e.g. aac.",
+        long_help = "Re-encode the audio of the output video with this codec, e.g. aac. By default the audio is copied as is, which fails when the codec of the input does not fit the output container. Only works on videos."
+    )]
+    audio_codec: Option<String>,
+
+    /// Hardware encoder used for the output video.
+    ///
+    /// See also: [`Cli::hwaccel()`]
+    #[clap(
+        long,
+        arg_enum,
+        value_name = "Accelerator",
+        help = "Encode the output video on the GPU with NVENC, Quick Sync or VA-API.",
+        long_help = "Encode the output video on the GPU with NVENC, Quick Sync or VA-API. Much faster than software encoding for long videos, but --preset is ignored and --crf is mapped to the quality option of the encoder. Only works on videos."
+    )]
+    hwaccel: Option<HwAccel>,
+
+    /// Delay of every output GIF frame in milliseconds.
+    ///
+    /// See also: [`Cli::frame_delay()`]
+    #[clap(
+        long,
+        value_name = "Milliseconds",
+        help = "Play every frame of the GIF for this many milliseconds.",
+        long_help = "Play every frame of the GIF for this many milliseconds, replacing its own timing. Delays under 20ms are raised to 20ms, as browsers slow down shorter delays. Only works on GIFs."
+    )]
+    frame_delay: Option<u32>,
+
+    /// Playback speed of the output GIF.
+    ///
+    /// See also: [`Cli::speed()`]
+    #[clap(
+        long,
+        value_name = "Factor",
+        help = "Play the GIF faster or slower, e.g. 2.0 plays it twice as fast.",
+        long_help = "Play the GIF faster or slower, e.g. 2.0 halves every frame delay and 0.5 doubles it. Applies on top of --frame-delay. Sped up delays are not shortened below 20ms, as browsers slow down shorter delays. Only works on GIFs.",
+        parse(try_from_str = parse_speed),
+    )]
+    speed: Option<f32>,
+
+    /// Speed of the GIF encoder, trading color quality for time.
+    ///
+    /// See also: [`Cli::gif_quality()`]
+    #[clap(
+        long,
+        value_name = "1-30",
+        help = "Speed of the GIF encoder from 1 (best colors, slowest) to 30 (fastest).",
+        long_help = "Speed of the GIF encoder from 1 to 30. Lower values pick every frame's palette more carefully, giving better colors but taking longer, 1 is best and slowest and 30 is fastest. This is separate from the Gifsicle optimization done afterwards. Defaults to 30. Only works on GIFs.",
+        parse(try_from_str = parse_gif_quality),
+    )]
+    gif_quality: Option<i32>,
+
+    /// File format captioned GIFs are written in.
+    ///
+    /// See also: [`Cli::output_format()`]
+    #[clap(
+        long,
+        arg_enum,
+        value_name = "Format",
+        default_value = "gif",
+        help = "File format of the output of GIF inputs.",
+        long_help = "File format of the output of GIF inputs: gif or webp. WebP output is converted with FFmpeg and cannot be optimized with Gifsicle. Only works on GIFs."
+    )]
+    format: OutputFormat,
+
+    /// Number of times the output GIF repeats.
+    ///
+    /// See also: [`Cli::loops()`]
+    #[clap(
+        long,
+        value_name = "N",
+        default_value = "0",
+        help = "Repeat the GIF N times. 0 repeats it forever.",
+        long_help = "Repeat the GIF N times. 0 repeats it forever. Gifsicle keeps the loop count when optimizing. Only works on GIFs."
+    )]
+    loops: u16,
+
+    /// Offset of the drop shadow drawn behind the caption text.
+    ///
+    /// See also: [`Cli::shadow()`]
+    #[clap(
+        long,
+        value_name = "dx,dy",
+        help = "Draw a drop shadow behind the caption, offset by <dx>,<dy> pixels.",
+        long_help = None,
+        parse(try_from_str = parse_offset),
+        allow_hyphen_values = true,
+    )]
+    shadow: Option<(i32, i32)>,
+
+    /// Color of the drop shadow.
+    ///
+    /// Only used if [`Cli::shadow`] is set.
+    #[clap(
+        long,
+        value_name = "Hex",
+        help = "Color of the drop shadow as #RRGGBB or #RRGGBBAA. Defaults to translucent black.",
+        long_help = None,
+        parse(try_from_str = parse_color),
+    )]
+    shadow_color: Option<Rgba<u8>>,
+
+    /// Blur applied to the drop shadow.
+    ///
+    /// Only used if [`Cli::shadow`] is set.
+    #[clap(
+        long,
+        value_name = "Sigma",
+        help = "Blur the drop shadow. Higher values give a softer shadow.",
+        long_help = None,
+    )]
+    shadow_blur: Option<f32>,
+
+    /// Color of the outline drawn around the caption text.
+    ///
+    /// See also: [`Cli::stroke()`]
+    #[clap(
+        long,
+        value_name = "Hex",
+        help = "Draw an outline around the caption text in this color, as #RRGGBB or #RRGGBBAA.",
+        long_help = None,
+        parse(try_from_str = parse_color),
+    )]
+    stroke: Option<Rgba<u8>>,
+
+    /// Width of the outline in pixels.
+    ///
+    /// Only used if [`Cli::stroke`] is set.
+    #[clap(
+        long,
+        value_name = "Pixels",
+        default_value = "2",
+        help = "Width of the outline around the caption text.",
+        long_help = None,
+    )]
+    stroke_width: u32,
+
+    /// Factor the caption's text size is multiplied by.
+    ///
+    /// See also: [`Cli::font_scale()`]
+    #[clap(
+        long,
+        value_name = "Factor",
+        help = "Multiply the size of the caption text, e.g. 2.0 doubles it.",
+        long_help = "Multiply the size of the caption text, e.g. 2.0 doubles it. Enlarged text is shrunk back as far as needed for every word to fit the media's width."
+    )]
+    font_scale: Option<f32>,
+
+    /// Index of the face to use within a font collection.
+    ///
+    /// Variable fonts always render their default instance.
+    ///
+    /// See also: [`Cli::font_index()`]
+    #[clap(
+        long,
+        value_name = "Index",
+        help = "Select a face within a font collection (.ttc). Defaults to the first face.",
+        long_help = None,
+        default_value = "0",
+    )]
+    font_index: u32,
+
+    /// Weight of the face to use within a font collection.
+    ///
+    /// See also: [`Cli::font_weight()`]
+    #[clap(
+        long,
+        value_name = "Weight",
+        help = "Select the face of a font collection (.ttc) closest to this weight, e.g. 700 for bold.",
+        long_help = "Select the face of a font collection (.ttc) with the weight closest to this one, from 100 (thin) over 400 (regular) to 900 (black). Variable fonts always render their default instance.",
+        conflicts_with = "font-index",
+        parse(try_from_str = parse_font_weight),
+    )]
+    font_weight: Option<u16>,
+
+    /// Fonts to render the caption with instead of the built-in iFunny font.
+    ///
+    /// See also: [`Cli::fonts()`]
+    #[clap(
+        long,
+        parse(from_os_str),
+        value_name = "Path",
+        value_hint = ValueHint::FilePath,
+        help = "Use a TTF/OTF font instead of the built-in iFunny font. Can be given multiple times.",
+        long_help = None,
+    )]
+    font: Vec<PathBuf>,
+
+    /// Font used for every wrapped line, by its position in `--font`.
+    ///
+    /// See also: [`Cli::line_fonts()`]
+    #[clap(
+        long,
+        value_name = "Indices",
+        help = "Comma separated font (by --font position) for each wrapped line. Other lines use the first font.",
+        long_help = None,
+        use_value_delimiter = true,
+    )]
+    line_fonts: Vec<usize>,
+
+    /// Height of the blank gap between the caption and the media.
+    ///
+    /// See also: [`Cli::gap()`]
+    #[clap(
+        long,
+        value_name = "Pixels",
+        help = "Insert a blank gap of this many pixels between the caption and the media.",
+        long_help = None,
+        default_value = "0",
+    )]
+    gap: u32,
+
+    /// Whitespace above and below the caption text in pixels.
+    ///
+    /// See also: [`Cli::padding()`]
+    #[clap(
+        long,
+        value_name = "Pixels",
+        help = "Whitespace above and below the caption text in pixels.",
+        long_help = "Whitespace above and below the caption text in pixels. Defaults to half the height of the text, so larger captions get more room."
+    )]
+    padding: Option<u32>,
+
+    /// Color of the gap between the caption and the media.
+    ///
+    /// See also: [`Cli::gap_color()`]
+    #[clap(
+        long,
+        value_name = "Hex",
+        help = "Color of the gap as #RRGGBB or #RRGGBBAA. Defaults to the caption's background color.",
+        long_help = None,
+        parse(try_from_str = parse_color),
+    )]
+    gap_color: Option<Rgba<u8>>,
+
+    /// Color of the caption text.
+    ///
+    /// See also: [`Cli::text_color()`]
+    #[clap(
+        long,
+        value_name = "Hex",
+        help = "Color of the caption text as #RRGGBB or #RRGGBBAA. Defaults to black.",
+        long_help = None,
+        parse(try_from_str = parse_color),
+    )]
+    text_color: Option<Rgba<u8>>,
+
+    /// Color of the caption background.
+    ///
+    /// See also: [`Cli::bg_color()`]
+    #[clap(
+        long,
+        value_name = "Hex",
+        help = "Color of the caption background as #RRGGBB or #RRGGBBAA. Defaults to white.",
+        long_help = None,
+        parse(try_from_str = parse_color),
+    )]
+    bg_color: Option<Rgba<u8>>,
+
+    /// Height of the fade between the caption and the media.
+    ///
+    /// See also: [`Cli::feather()`]
+    #[clap(
+        long,
+        value_name = "Pixels",
+        help = "Fade the bottom edge of the caption into the media over this many pixels.",
+        long_help = None,
+        default_value = "0",
+    )]
+    feather: u32,
+
+    /// Image drawn over a corner of the captioned media.
+    ///
+    /// See also: [`Cli::watermark()`]
+    #[clap(
+        long,
+        parse(from_os_str),
+        value_name = "Image",
+        value_hint = ValueHint::FilePath,
+        help = "Draw an image, e.g. a PNG logo, over a corner of the captioned media.",
+        long_help = "Draw an image, e.g. a PNG logo, over a corner of the captioned media. The watermark is scaled to a fifth of the output's width and keeps its transparency.",
+    )]
+    watermark: Option<PathBuf>,
+
+    /// Corner the watermark is drawn in.
+    ///
+    /// See also: [`Cli::watermark_corner()`]
+    #[clap(
+        long = "watermark-pos",
+        arg_enum,
+        value_name = "Corner",
+        help = "Corner the watermark is drawn in.",
+        long_help = None,
+        default_value = "bottom-right",
+    )]
+    watermark_corner: Corner,
+
+    /// Opacity of the watermark.
+    ///
+    /// See also: [`Cli::watermark_opacity()`]
+    #[clap(
+        long,
+        value_name = "Opacity",
+        help = "Opacity of the watermark, from 0 to 1.",
+        long_help = None,
+        default_value = "1",
+        parse(try_from_str = parse_opacity),
+    )]
+    watermark_opacity: f32,
+
+    /// Animation revealing the caption over the frames of a GIF.
+    ///
+    /// See also: [`Cli::caption_animation()`]
+    #[clap(
+        long,
+        arg_enum,
+        value_name = "Animation",
+        help = "Reveal the caption over the first half of a GIF's frames.",
+        long_help = "Reveal the caption over the first half of a GIF's frames. Typewriter reveals it character by character, scrollup slides it in from below. Only works on GIFs."
+    )]
+    caption_animate: Option<CaptionAnimation>,
+
+    /// Side of the media the caption is placed on.
+    ///
+    /// See also: [`Cli::position()`]
+    #[clap(
+        long,
+        arg_enum,
+        value_name = "Position",
+        help = "Place the caption above or below the media.",
+        long_help = None,
+        default_value = "top"
+    )]
+    position: CaptionPosition,
+
+    /// Whether the caption is added next to the media or drawn over it.
+    ///
+    /// See also: [`Cli::overlay_mode()`]
+    #[clap(
+        long,
+        arg_enum,
+        value_name = "Mode",
+        help = "Add the caption next to the media (pad) or draw it over the media (over).",
+        long_help = "Add the caption next to the media, making the output taller (pad), or draw it over the top or bottom of the media, keeping its dimensions (over).",
+        default_value = "pad"
+    )]
+    overlay_mode: OverlayMode,
+
+    /// Opacity of a caption drawn over the media.
+    ///
+    /// See also: [`Cli::overlay_opacity()`]
+    #[clap(
+        long,
+        value_name = "Opacity",
+        help = "Opacity of the caption with --overlay-mode over, from 0 to 1.",
+        long_help = None,
+        default_value = "1",
+        parse(try_from_str = parse_opacity),
+    )]
+    overlay_opacity: f32,
+
+    /// Alignment of multi-line captions.
+    ///
+    /// See also: [`Cli::align()`]
+    #[clap(
+        long,
+        arg_enum,
+        value_name = "Alignment",
+        help = "Alignment of the caption's lines.",
+        long_help = "Alignment of the caption's lines. Left and right move the lines to the margin of the caption, justify stretches the space between words so every line but the last spans the caption's width.",
+        default_value = "center"
+    )]
+    align: TextAlign,
+
+    /// Where long captions may be broken into lines.
+    ///
+    /// See also: [`Cli::wrap_mode()`]
+    #[clap(
+        long,
+        arg_enum,
+        value_name = "Mode",
+        help = "Where long captions may be broken into lines.",
+        long_help = "Where long captions may be broken into lines. Word only breaks between words, char also breaks between characters, auto breaks between characters only in words of scripts without spaces like Chinese or Japanese. Words wider than the media, like long URLs, are always broken between characters.",
+        default_value = "auto"
+    )]
+    wrap_mode: WrapMode,
+
+    /// Determines whether rendered captions should be cached on disk.
+    ///
+    /// See also: [`Cli::caption_cache_dir()`]
+    #[clap(
+        long,
+        help = "Cache rendered captions on disk so repeated captions are only rendered once.",
+        long_help = None,
+    )]
+    cache_captions: bool,
+
+    /// Determines whether the caption is only sized instead of rendered onto the media.
+    ///
+    /// See also: [`Cli::dry_run()`]
+    #[clap(
+        long,
+        help = "Print the output path, the media's dimensions and the caption's height without writing anything.",
+        long_help = None,
+    )]
+    dry_run: bool,
+
+    /// Determines whether only a preview of the first frame is rendered.
+    ///
+    /// See also: [`Cli::preview()`]
+    #[clap(
+        long,
+        help = "Render the caption onto the first frame only and open it, without processing the media.",
+        long_help = "Render the caption onto the first frame only and open it, without processing the media. The preview is a PNG in the temporary directory. Run again without --preview to render the whole media.",
+        conflicts_with = "dry-run"
+    )]
+    preview: bool,
+
+    /// Determines whether the jobs are only listed instead of processed.
+    ///
+    /// See also: [`Cli::list_jobs()`]
+    #[clap(
+        long,
+        help = "Print every input with the output it would be written to, without processing anything.",
+        long_help = None,
+    )]
+    list_jobs: bool,
+
+    /// Directory intermediate files are written to for debugging.
+    ///
+    /// See also: [`Cli::debug_dump()`]
+    #[clap(
+        long,
+        parse(from_os_str),
+        value_name = "Directory",
+        value_hint = ValueHint::DirPath,
+        help = "Write the caption, the first captioned frame and the FFmpeg command to this directory.",
+        long_help = None,
+    )]
+    debug_dump: Option<PathBuf>,
+
+    /// Path of the `FFmpeg` executable.
+    ///
+    /// See also: [`Cli::ffmpeg_path()`]
+    #[clap(
+        long,
+        parse(from_os_str),
+        value_name = "File",
+        value_hint = ValueHint::ExecutablePath,
+        help = "Run this FFmpeg executable. Setting UNLUSTIG_FFMPEG does the same.",
+        long_help = None,
+    )]
+    ffmpeg_path: Option<PathBuf>,
+
+    /// Time FFmpeg and Gifsicle may run for.
+    ///
+    /// See also: [`Cli::timeout()`]
+    #[clap(
+        long,
+        value_name = "Seconds",
+        help = "Stop FFmpeg and Gifsicle if they run for longer than this. Setting UNLUSTIG_TIMEOUT does the same.",
+        long_help = "Stop FFmpeg and Gifsicle if they run for longer than this many seconds, e.g. on a malformed file. Setting UNLUSTIG_TIMEOUT does the same. 0 or no value lets them run for as long as they need."
+    )]
+    timeout: Option<u64>,
+
+    /// Path of the Gifsicle executable.
+    ///
+    /// See also: [`Cli::gifsicle_path()`]
+    #[clap(
+        long,
+        parse(from_os_str),
+        value_name = "File",
+        value_hint = ValueHint::ExecutablePath,
+        help = "Run this Gifsicle executable. Setting UNLUSTIG_GIFSICLE does the same.",
+        long_help = None,
+    )]
+    gifsicle_path: Option<PathBuf>,
+
+    /// Directory temporary files are written to.
+    ///
+    /// See also: [`Cli::temp_dir()`]
+    #[clap(
+        long,
+        parse(from_os_str),
+        value_name = "Directory",
+        value_hint = ValueHint::DirPath,
+        help = "Write temporary files to this directory instead of the system's temporary directory.",
+        long_help = None,
+    )]
+    temp_dir: Option<PathBuf>,
+
+    /// File that structured progress events are written to.
+    ///
+    /// See also: [`Cli::events()`]
+    #[clap(
+        long,
+        value_name = "Path",
+        help = "Write newline-delimited JSON progress events to this file.",
+        long_help = None,
+        parse(from_os_str),
+        value_hint = ValueHint::FilePath,
+    )]
+    events: Option<PathBuf>,
+
+    /// How much more than usual is logged.
+    ///
+    /// See also: [`Cli::log_level()`]
+    #[clap(
+        short = 'v',
+        long,
+        parse(from_occurrences),
+        help = "Log more details. Repeat to log even more, e.g. -vv.",
+        long_help = None,
+    )]
+    verbose: u64,
+
+    /// Determines whether only warnings and errors are logged.
+    ///
+    /// See also: [`Cli::log_level()`]
+    #[clap(
+        short = 'q',
+        long,
+        conflicts_with = "verbose",
+        help = "Only log warnings and errors.",
+        long_help = None,
+    )]
+    quiet: bool,
+
+    /// Determines whether the outcome is printed as JSON.
+    ///
+    /// See also: [`Cli::json()`]
+    #[clap(
+        long,
+        help = "Print a JSON object describing the output instead of the usual log lines.",
+        long_help = "Print a JSON object describing the output instead of the usual log lines. It holds the output path, the input media type, the output dimensions, the number of caption lines and whether the output was optimized. Warnings and errors are still logged to stderr."
+    )]
+    json: bool,
+
+    /// Determines whether the GitHub update check is skipped.
+    ///
+    /// See also: [`Cli::no_update_check()`]
+    #[clap(
+        long,
+        help = "Do not check GitHub for a newer release. Setting UNLUSTIG_NO_UPDATE does the same.",
+        long_help = None,
+    )]
+    no_update_check: bool,
+
+    /// Determines whether Discord Rich Presence is disabled.
+    ///
+    /// See also: [`Cli::no_rpc()`]
+    #[clap(
+        long,
+        help = "Do not connect to Discord for Rich Presence. Setting UNLUSTIG_NO_RPC does the same.",
+        long_help = None,
+    )]
+    no_rpc: bool,
+
+    /// Determines whether the output is not opened once it is written.
+    ///
+    /// See also: [`Cli::opens_output()`]
+    #[clap(
+        long,
+        help = "Do not open the output or the preview in the file manager. Setting UNLUSTIG_NO_OPEN does the same.",
+        long_help = "Do not open the output or the preview in the file manager, only log where it was written. Setting UNLUSTIG_NO_OPEN does the same. Nothing is opened either when no display is available."
+    )]
+    no_open: bool,
+
+    /// The opened event log.
+    ///
+    /// See also: [`Cli::open_event_log()`]
+    #[clap(skip)]
+    event_log: EventLog,
+
+    /// Media read from stdin, buffered to a temporary file.
+    ///
+    /// See also: [`Cli::buffer_stdin_media()`]
+    #[clap(skip)]
+    stdin_media: Option<TempFile>,
+}
+
+/// Actions that can be run instead of captioning.
+#[derive(Subcommand, Debug, Clone, PartialEq, Eq)]
+pub enum Action {
+    /// Remove downloaded dependencies, cached captions and temporary files older than a day.
+    Clean,
+    /// Print the type, dimensions and duration of media without captioning it.
+    Probe {
+        /// The media to probe.
+        #[clap(parse(from_os_str), value_name = "Media", value_hint = ValueHint::FilePath)]
+        media: PathBuf,
+    },
+    /// Print the supported media formats and what they can be converted to.
+    ListFormats,
+    /// Replace this executable with the newest release from GitHub.
+    SelfUpdate {
+        /// Update without asking for confirmation.
+        #[clap(long, short)]
+        yes: bool,
+    },
+}
+
+/// Animations revealing the caption over the frames of a GIF.
+#[derive(ArgEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CaptionAnimation {
+    /// The caption is revealed character by character.
+    Typewriter,
+    /// The caption slides in from below.
+    Scrollup,
+}
+
+/// Side of the media the caption is placed on.
+#[derive(ArgEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CaptionPosition {
+    /// The caption is above the media.
+    Top,
+    /// The caption is below the media.
+    Bottom,
+}
+
+/// How the caption is added to the media.
+#[derive(ArgEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OverlayMode {
+    /// The media is padded with the caption, the output is taller than the media.
+    Pad,
+    /// The caption is drawn over the media, the output is as large as the media.
+    Over,
+}
+
+/// Corner of the output an image is drawn in.
+#[derive(ArgEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Corner {
+    /// The top left corner.
+    TopLeft,
+    /// The top right corner.
+    TopRight,
+    /// The bottom left corner.
+    BottomLeft,
+    /// The bottom right corner.
+    BottomRight,
+}
+
+/// Alignment of multi-line captions.
+#[derive(ArgEnum, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum TextAlign {
+    /// Every line is centered.
+    Center,
+    /// Every line starts at the left margin.
+    Left,
+    /// Every line ends at the right margin.
+    Right,
+    /// Every line but the last spans the full caption width.
+    Justify,
+}
+
+/// What to do when the media has no sidecar caption.
+#[derive(ArgEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MissingSidecar {
+    /// The caption given with `--caption` is used.
+    Caption,
+    /// The media is not processed.
+    Skip,
+}
+
+/// Color range of a video.
+#[derive(ArgEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ColorRange {
+    /// Limited range, used by most videos.
+    Tv,
+    /// Full range.
+    Pc,
+}
+
+impl ColorRange {
+    /// Returns the name `FFmpeg` uses for the range.
+    pub const fn as_str(self) -> &'static str {
+        match self {
+            Self::Tv => "tv",
+            Self::Pc => "pc",
+        }
+    }
+}
+
+/// File format of the output of a GIF.
+#[derive(ArgEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OutputFormat {
+    /// An animated GIF.
+    Gif,
+    /// An animated WebP.
+    Webp,
+}
+
+/// Dithering method of Gifsicle's color reduction.
+#[derive(ArgEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Dither {
+    /// Error diffusion, the smoothest on photos.
+    FloydSteinberg,
+    /// Gifsicle's default ordered dithering.
+    Ordered,
+    /// A large, random-looking ordered pattern.
+    Ro64,
+    /// A 3x3 ordered pattern.
+    O3,
+    /// A 4x4 ordered pattern.
+    O4,
+    /// An 8x8 ordered pattern.
+    O8,
+    /// A halftone pattern of dots.
+    Halftone,
+}
+
+impl Dither {
+    /// Returns the name Gifsicle uses for the method.
+    pub const fn as_str(self) -> &'static str {
+        match self {
+            Self::FloydSteinberg => "floyd-steinberg",
+            Self::Ordered => "ordered",
+            Self::Ro64 => "ro64",
+            Self::O3 => "o3",
+            Self::O4 => "o4",
+            Self::O8 => "o8",
+            Self::Halftone => "halftone",
+        }
+    }
+}
+
+/// Hardware accelerated H.264 encoder of a video.
+#[derive(ArgEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HwAccel {
+    /// NVIDIA's NVENC.
+    Nvenc,
+    /// Intel's Quick Sync Video.
+    Qsv,
+    /// VA-API, as provided by Mesa or Intel drivers on Linux.
+    Vaapi,
+}
+
+impl HwAccel {
+    /// Returns the name of the `FFmpeg` encoder.
+    pub const fn encoder(self) -> &'static str {
+        match self {
+            Self::Nvenc => "h264_nvenc",
+            Self::Qsv => "h264_qsv",
+            Self::Vaapi => "h264_vaapi",
+        }
+    }
+
+    /// Returns the `FFmpeg` option that sets the constant quality of the encoder.
+    pub const fn quality_flag(self) -> &'static str {
+        match self {
+            Self::Nvenc => "-cq",
+            Self::Qsv => "-global_quality",
+            Self::Vaapi => "-qp",
+        }
+    }
+}
+
+/// Encoder preset of a video, as known by x264.
+#[derive(ArgEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Preset {
+    /// The fastest preset.
+    Ultrafast,
+    /// Faster than veryfast.
+    Superfast,
+    /// Faster than faster.
+    Veryfast,
+    /// Faster than fast.
+    Faster,
+    /// Faster than medium.
+    Fast,
+    /// The default preset.
+    Medium,
+    /// Slower than medium.
+    Slow,
+    /// Slower than slow.
+    Slower,
+    /// Slower than slower.
+    Veryslow,
+    /// The slowest preset.
+    Placebo,
+}
+
+impl Preset {
+    /// Returns the name `FFmpeg` uses for the preset.
+    pub const fn as_str(self) -> &'static str {
+        match self {
+            Self::Ultrafast => "ultrafast",
+            Self::Superfast => "superfast",
+            Self::Veryfast => "veryfast",
+            Self::Faster => "faster",
+            Self::Fast => "fast",
+            Self::Medium => "medium",
+            Self::Slow => "slow",
+            Self::Slower => "slower",
+            Self::Veryslow => "veryslow",
+            Self::Placebo => "placebo",
+        }
+    }
+}
+
+/// Where long captions may be broken into lines.
+#[derive(ArgEnum, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum WrapMode {
+    /// Lines only break between words, unless a word is wider than the media.
+    Word,
+    /// Lines may also break between any two characters.
+    Char,
+    /// Lines also break between characters of CJK words.
+    Auto,
+}
+
+impl WrapMode {
+    /// Returns true if `word` may be broken between its characters.
+    pub fn breaks_within(self, word: &str) -> bool {
+        match self {
+            Self::Word => false,
+            Self::Char => true,
+            Self::Auto => word.chars().any(is_cjk),
+        }
+    }
+}
+
+/// Returns true if `c` belongs to a script that is written without spaces.
+const fn is_cjk(c: char) -> bool {
+    matches!(c,
+        '\u{3000}'..='\u{303f}' // CJK symbols and punctuation
+        | '\u{3040}'..='\u{30ff}' // Hiragana and Katakana
+        | '\u{3400}'..='\u{4dbf}' // CJK extension A
+        | '\u{4e00}'..='\u{9fff}' // CJK unified ideographs
+        | '\u{ac00}'..='\u{d7af}' // Hangul syllables
+        | '\u{f900}'..='\u{faff}' // CJK compatibility ideographs
+        | '\u{ff00}'..='\u{ffef}' // halfwidth and fullwidth forms
+        | '\u{20000}'..='\u{2fa1f}' // CJK extensions B and later
+    )
+}
+
+impl Cli {
+    /// Returns the action to run instead of captioning.
+    ///
+    /// # Option
+    /// Returns [`None`] if media should be captioned.
+    pub fn action(&self) -> Option<&Action> {
+        self.action.as_ref()
+    }
+
+    /// Returns the side of the media the caption is placed on.
+    pub fn position(&self) -> CaptionPosition {
+        self.position
+    }
+
+    /// Returns whether the caption is added next to the media or drawn over it.
+    pub fn overlay_mode(&self) -> OverlayMode {
+        self.overlay_mode
+    }
+
+    /// Returns the opacity of the caption, from 0 to 1.
+    ///
+    /// Captions next to the media are always opaque, there is nothing behind them.
+    pub fn overlay_opacity(&self) -> f32 {
+        match self.overlay_mode {
+            OverlayMode::Pad => 1.0,
+            OverlayMode::Over => self.overlay_opacity,
+        }
+    }
+
+    /// Returns the alignment of multi-line captions.
+    pub fn align(&self) -> TextAlign {
+        self.align
+    }
+
+    /// Returns where long captions may be broken into lines.
+    pub fn wrap_mode(&self) -> WrapMode {
+        self.wrap_mode
+    }
+
+    /// Returns the animation revealing the caption.
+    ///
+    /// # Option
+    /// Returns [`None`] if the caption is not animated.
+    pub fn caption_animation(&self) -> Option<CaptionAnimation> {
+        self.caption_animate
+    }
+
+    /// Returns the path of the pre-rendered caption image.
+    ///
+    /// # Option
+    /// Returns [`None`] if the caption should be rendered from text.
+    pub fn caption_image(&self) -> Option<&Path> {
+        self.caption_image.as_deref()
+    }
+
+    /// Returns the directory temporary files should be written to.
+    ///
+    /// # Option
+    /// Returns [`None`] if the system's temporary directory should be used.
+    pub fn temp_dir(&self) -> Option<&Path> {
+        self.temp_dir.as_deref()
+    }
+
+    /// Returns the directory rendered captions are cached in.
+    ///
+    /// # Option
+    /// Returns [`None`] if caption caching is disabled or
+    /// the platform has no cache directory.
+    pub fn caption_cache_dir(&self) -> Option<PathBuf> {
+        if !self.cache_captions {
+            return None;
+        }
+        dirs::cache_dir().map(|dir| dir.join("unlustig-rs").join("captions"))
+    }
+
+    /// Returns the event log of the run.
+    ///
+    /// Events are discarded unless [`Cli::open_event_log()`] opened a file.
+    pub fn events(&self) -> &EventLog {
+        &self.event_log
+    }
+
+    /// Opens the event log file if one was given.
+    ///
+    /// # Errors
+    /// Returns an error if the event log file cannot be created.
+    pub fn open_event_log(&mut self) -> Result<()> {
+        if let Some(path) = &self.events {
+            self.event_log = EventLog::create(path)?;
+        }
+        Ok(())
+    }
+
+    /// Returns the height of the gap between the caption and the media.
+    pub fn gap(&self) -> u32 {
+        self.gap
+    }
+
+    /// Returns the whitespace above and below the caption text.
+    ///
+    /// # Option
+    /// Returns [`None`] if the padding should be derived from the text size.
+    pub fn padding(&self) -> Option<u32> {
+        self.padding
+    }
+
+    /// Returns the height of the fade between the caption and the media.
+    pub fn feather(&self) -> u32 {
+        self.feather
+    }
+
+    /// Returns the path of the watermark image.
+    ///
+    /// # Option
+    /// Returns [`None`] if no watermark was given.
+    pub fn watermark(&self) -> Option<&Path> {
+        self.watermark.as_deref()
+    }
+
+    /// Returns the corner the watermark is drawn in.
+    pub fn watermark_corner(&self) -> Corner {
+        self.watermark_corner
+    }
+
+    /// Returns the opacity of the watermark, from 0 to 1.
+    pub fn watermark_opacity(&self) -> f32 {
+        self.watermark_opacity
+    }
+
+    /// Returns the color of the gap between the caption and the media.
+    ///
+    /// Defaults to the color of the caption background.
+    pub fn gap_color(&self) -> Rgba<u8> {
+        self.gap_color.unwrap_or_else(|| self.bg_color())
+    }
+
+    /// Returns the color of the caption text.
+    pub fn text_color(&self) -> Rgba<u8> {
+        self.text_color.unwrap_or(Rgba([0, 0, 0, 255]))
+    }
+
+    /// Returns the color of the caption background.
+    pub fn bg_color(&self) -> Rgba<u8> {
+        self.bg_color.unwrap_or(Rgba([255, 255, 255, 255]))
+    }
+
+    /// Returns the factor the caption's text size is multiplied by.
+    ///
+    /// Returns `1.0` if no factor or a non-positive one was given.
+    pub fn font_scale(&self) -> f32 {
+        self.font_scale
+            .filter(|scale| scale.is_finite() && *scale > 0.0)
+            .unwrap_or(1.0)
+    }
+
+    /// Returns the index of the face to load from the font.
+    pub fn font_index(&self) -> u32 {
+        self.font_index
+    }
+
+    /// Returns the weight of the face to load from the font.
+    ///
+    /// # Option
+    /// Returns [`None`] if the face is selected by [`Cli::font_index()`].
+    pub fn font_weight(&self) -> Option<u16> {
+        self.font_weight
+    }
+
+    /// Returns the font files given with `--font`.
+    ///
+    /// The built-in font is used if this is empty.
+    pub fn fonts(&self) -> &[PathBuf] {
+        &self.font
+    }
+
+    /// Returns the index of the font for every wrapped line.
+    pub fn line_fonts(&self) -> &[usize] {
+        &self.line_fonts
+    }
+
+    /// Returns an identifier of the loaded fonts for the caption cache.
+    pub fn font_id(&self) -> String {
+        font_id(&self.font, self.font_index, self.font_weight)
+    }
+
+    /// Returns the lossiness level.
+    ///
+    /// # Option
+    /// Returns `None` if no lossiness was given.
+    pub fn lossy(&self) -> Option<u32> {
+        self.lossy
+    }
+
+    /// Replaces the caption with the sidecar caption of the media.
+    ///
+    /// The sidecar of `cat.gif` is `cat.txt`. Media read from stdin has no sidecar.
+    /// Returns `false` if the media has no sidecar and should be skipped.
+    ///
+    /// # Errors
+    /// Returns an error if the sidecar cannot be read, or [`NoTextGiven`]
+    /// if it is missing and no caption was given to fall back to.
+    ///
+    /// [`NoTextGiven`]: crate::error::ErrorKind::NoTextGiven
+    pub fn load_sidecar_caption(&mut self) -> Result<bool> {
+        if !self.caption_from_sidecar || self.media == Path::new("-") {
+            return Ok(true);
+        }
+        let sidecar = self.media.with_extension("txt");
+        match fs::read_to_string(&sidecar) {
+            Ok(caption) => {
+                let lines: Vec<_> = caption.lines().map(str::trim).collect();
+                self.caption = Some(lines.join("\\n"));
+                Ok(true)
+            }
+            Err(err) if err.kind() == io::ErrorKind::NotFound => match self.missing_sidecar {
+                MissingSidecar::Caption if self.caption.is_some() => Ok(true),
+                MissingSidecar::Caption => Err(anyhow::Error::new(ErrorKind::NoTextGiven)
+                    .context(format!("no sidecar caption found at {}", sidecar.display()))),
+                MissingSidecar::Skip => Ok(false),
+            },
+            Err(err) => Err(err).context(format!(
+                "failed to read sidecar caption: {}",
+                sidecar.display()
+            )),
+        }
+    }
+
+    /// Buffers media piped through stdin into a temporary file.
+    ///
+    /// Does nothing unless the media path is `-`. Both the GIF decoder and FFmpeg
+    /// need a seekable source, so stdin is read to the end and its format is
+    /// detected from its leading bytes. The temporary file is removed when
+    /// [`Cli`] is dropped.
+    ///
+    /// # Errors
+    /// Returns an error if stdin cannot be read or written to the temporary file,
+    /// or an [`UnsupportedMediaFormat`] error if its format is not recognized.
+    ///
+    /// [`UnsupportedMediaFormat`]: crate::error::ErrorKind::UnsupportedMediaFormat
+    pub fn buffer_stdin_media(&mut self) -> Result<()> {
+        if self.media != Path::new("-") {
+            return Ok(());
+        }
+        let mut bytes = Vec::new();
+        io::stdin()
+            .lock()
+            .read_to_end(&mut bytes)
+            .context("failed to read media from stdin")?;
+        let ty = sniff_format(&bytes).ok_or_else(|| {
+            ErrorKind::UnsupportedMediaFormat("unrecognized media on stdin".to_string())
+        })?;
+
+        let temp = self.tools().temp_file(ty.extension());
+        fs::write(temp.path(), bytes).context("failed to buffer stdin media")?;
+        self.media = temp.path().to_path_buf();
+        self.stdin_media = Some(temp);
+        Ok(())
+    }
+
+    /// Returns true if the media is read from stdin, given as `-`.
+    pub fn reads_stdin(&self) -> bool {
+        self.media == Path::new("-") || self.stdin_media.is_some()
+    }
+
+    /// Returns a tuple of the input media's [`Path`] and [`Type`]
+    ///
+    /// # Errors
+    /// Returns an [`UnsupportedMediaFormat`] error if
+    /// the input file is unsupported.
+    ///
+    /// [`UnsupportedMediaFormat`]: crate::error::ErrorKind::UnsupportedMediaFormat
+    /// [`Path`]: std::path::Path
+    /// [`Type`]: crate::utils::MediaType
+    pub fn media(&self) -> Result<(PathBuf, MediaType)> {
+        Ok((self.media.clone(), validate_format(&self.media)?))
+    }
+
+    /// Returns the name given for the output file.
+    ///
+    /// # Option
+    /// Returns [`None`] if a random name should be generated.
+    pub fn output_name(&self) -> Option<&str> {
+        self.output_name.as_deref()
+    }
+
+    /// Returns the name of the output media.
+    ///
+    /// The name of `--output-file` is used as is if it was given.
+    /// With `--suffix`, the suffix is appended to the stem of `--output-name`,
+    /// or of the input if no name was given.
+    ///
+    /// # Errors
+    /// Returns an [`UnsupportedMediaFormat`] error if
+    /// the input file is unsupported, or an [`OutputExtensionMismatch`]
+    /// error if the extension of `--output-file` does not fit the output.
+    ///
+    /// [`UnsupportedMediaFormat`]: crate::error::ErrorKind::UnsupportedMediaFormat
+    /// [`OutputExtensionMismatch`]: crate::error::ErrorKind::OutputExtensionMismatch
+    pub fn name(&self) -> Result<String> {
+        let (_, ty) = self.media()?;
+        let ext = output_extension(ty, self.to_gif, self.loop_video.is_some(), self.format);
+        if let Some(file) = &self.output_file {
+            return output_file_name(file, ext);
+        }
+        match (&self.output_name, &self.suffix) {
+            (Some(string), Some(suffix)) => {
+                let stem = string.strip_suffix(ext).unwrap_or(string);
+                Ok(format!("{stem}{suffix}{ext}"))
+            }
+            (Some(string), None) => {
+                if !string.contains(ext) {
+                    return Ok(format!("{}{}", string, ext));
+                }
+                Ok(string.to_owned())
+            }
+            (None, Some(suffix)) => {
+                // media read from stdin has no name to build on, only the temporary file's.
+                let stem = if self.reads_stdin() {
+                    random_output_name()
+                } else {
+                    self.media
+                        .file_stem()
+                        .map_or_else(random_output_name, |stem| {
+                            stem.to_string_lossy().into_owned()
+                        })
+                };
+                Ok(format!("{stem}{suffix}{ext}"))
+            }
+            (None, None) => Ok(format!("{}{}", random_output_name(), ext)),
+        }
+    }
+
+    /// Returns true if the output is named randomly, without `--output-name` or `--suffix`.
+    pub fn names_randomly(&self) -> bool {
+        self.output_name.is_none() && self.suffix.is_none() && self.output_file.is_none()
+    }
+
+    /// Returns the maximum size of the output file in bytes.
+    ///
+    /// # Option
+    /// Returns [`None`] if the output size is not limited.
+    pub fn max_size(&self) -> Option<u64> {
+        self.max_size
+    }
+
+    /// Returns the delay of every output GIF frame in milliseconds.
+    ///
+    /// # Option
+    /// Returns [`None`] if the frames keep their own delays.
+    pub fn frame_delay(&self) -> Option<u32> {
+        self.frame_delay
+    }
+
+    /// Returns the playback speed of the output GIF.
+    ///
+    /// # Option
+    /// Returns [`None`] if the GIF plays at its own speed.
+    pub fn speed(&self) -> Option<f32> {
+        self.speed
+    }
+
+    /// Returns the speed of the GIF encoder, from 1 (best) to 30 (fastest).
+    ///
+    /// Returns 30 if no speed was given, so existing workflows are not slowed down.
+    pub fn gif_quality(&self) -> i32 {
+        self.gif_quality.unwrap_or(30)
+    }
+
+    /// Returns true if a GIF encoder speed was given.
+    pub fn has_gif_quality(&self) -> bool {
+        self.gif_quality.is_some()
+    }
+
+    /// Returns the file format captioned GIFs are written in.
+    pub fn output_format(&self) -> OutputFormat {
+        self.format
+    }
+
+    /// Returns the number of times the output GIF repeats.
+    ///
+    /// 0 means the GIF repeats forever.
+    pub fn loops(&self) -> u16 {
+        self.loops
+    }
+
+    /// Returns the number of threads `FFmpeg` should encode with.
+    ///
+    /// # Option
+    /// Returns [`None`] if `FFmpeg` should choose.
+    pub fn encoder_threads(&self) -> Option<u32> {
+        self.encoder_threads
+    }
+
+    /// Returns the requested color range of the output video.
+    ///
+    /// # Option
+    /// Returns [`None`] if the range of the input should be kept.
+    pub fn color_range(&self) -> Option<ColorRange> {
+        self.color_range
+    }
+
+    /// Returns the constant rate factor of the output video.
+    ///
+    /// # Option
+    /// Returns [`None`] if `FFmpeg` should choose.
+    pub fn crf(&self) -> Option<u32> {
+        self.crf
+    }
+
+    /// Returns the encoder preset of the output video.
+    ///
+    /// # Option
+    /// Returns [`None`] if `FFmpeg` should choose.
+    pub fn preset(&self) -> Option<Preset> {
+        self.preset
+    }
+
+    /// Returns true if the audio should be removed from the output video.
+    pub fn no_audio(&self) -> bool {
+        self.no_audio
+    }
+
+    /// Returns the codec the audio of the output video is re-encoded with.
+    ///
+    /// # Option
+    /// Returns [`None`] if the audio should be copied as is.
+    pub fn audio_codec(&self) -> Option<&str> {
+        self.audio_codec.as_deref()
+    }
+
+    /// Returns the hardware encoder of the output video.
+    ///
+    /// # Option
+    /// Returns [`None`] if the video should be encoded in software.
+    pub fn hwaccel(&self) -> Option<HwAccel> {
+        self.hwaccel
+    }
+
+    /// Returns true if a video should be output as a GIF.
+    pub fn to_gif(&self) -> bool {
+        self.to_gif
+    }
+
+    /// Returns the even width the captioned output is scaled to.
+    ///
+    /// # Option
+    /// Returns [`None`] if the output keeps the width of the media.
+    pub fn scale_output(&self) -> Option<u32> {
+        self.scale_output
+            .map(|width| width - width % 2)
+            .filter(|&width| width > 0)
+    }
+
+    /// Returns how many times the media plays in a looping video output.
+    ///
+    /// # Option
+    /// Returns [`None`] if no looping video was requested.
+    pub fn loop_video(&self) -> Option<u32> {
+        self.loop_video
+    }
+
+    /// Returns the comment to embed in the output GIF.
+    ///
+    /// # Option
+    /// Returns [`None`] if no comment should be embedded.
+    pub fn comment(&self) -> Option<&str> {
+        self.comment
+            .as_deref()
+            .filter(|comment| !comment.is_empty())
+    }
+
+    /// Returns the directory intermediate files are dumped to.
+    ///
+    /// # Option
+    /// Returns [`None`] if no intermediate files should be written.
+    pub fn debug_dump(&self) -> Option<&Path> {
+        self.debug_dump.as_deref()
+    }
+
+    /// Returns the most detailed level that should be logged.
+    ///
+    /// `--json` logs as little as `--quiet`.
+    pub fn log_level(&self) -> LevelFilter {
+        log_level(self.verbose, self.quiet || self.json)
+    }
+
+    /// Returns true if the outcome should be printed as JSON.
+    pub fn json(&self) -> bool {
+        self.json
+    }
+
+    /// Returns true if the update check was disabled with `--no-update-check`.
+    ///
+    /// The check runs before the arguments are parsed,
+    /// so the program looks the flag up in the raw arguments instead.
+    pub fn no_update_check(&self) -> bool {
+        self.no_update_check
+    }
+
+    /// Returns true if updates were disabled with `--no-update-check` or [`NO_UPDATE_VAR`].
+    ///
+    /// [`NO_UPDATE_VAR`]: crate::utils::update::NO_UPDATE_VAR
+    pub fn updates_disabled(&self) -> bool {
+        self.no_update_check || std::env::var_os(update::NO_UPDATE_VAR).is_some()
+    }
+
+    /// Returns true if Discord Rich Presence was disabled with `--no-rpc`.
+    ///
+    /// The connection is made before the arguments are parsed,
+    /// so the program looks the flag up in the raw arguments instead.
+    pub fn no_rpc(&self) -> bool {
+        self.no_rpc
+    }
+
+    /// Returns true if the output should be opened in the file manager once it is written.
+    ///
+    /// Nothing is opened with `--no-open`, `UNLUSTIG_NO_OPEN`, or without a display
+    /// to open it on, as over SSH or in CI.
+    pub fn opens_output(&self) -> bool {
+        if self.no_open || std::env::var_os("UNLUSTIG_NO_OPEN").is_some() {
+            return false;
+        }
+        // Windows always has a desktop, X11 and Wayland sessions set their display.
+        cfg!(windows)
+            || std::env::var_os("DISPLAY").is_some()
+            || std::env::var_os("WAYLAND_DISPLAY").is_some()
+    }
+
+    /// Returns true if the caption should only be sized.
+    pub fn dry_run(&self) -> bool {
+        self.dry_run
+    }
+
+    /// Returns true if only a preview of the first frame should be rendered.
+    pub fn preview(&self) -> bool {
+        self.preview
+    }
+
+    /// Returns the `FFmpeg` executable given with `--ffmpeg-path`.
+    ///
+    /// # Option
+    /// Returns [`None`] if `FFmpeg` should be looked up as usual.
+    pub fn ffmpeg_path(&self) -> Option<&Path> {
+        self.ffmpeg_path.as_deref()
+    }
+
+    /// Returns how long FFmpeg and Gifsicle may run for.
+    ///
+    /// # Option
+    /// Returns [`None`] if no timeout or a timeout of 0 was given.
+    pub fn timeout(&self) -> Option<Duration> {
+        self.timeout
+            .filter(|secs| *secs > 0)
+            .map(Duration::from_secs)
+    }
+
+    /// Returns the settings of the external programs the job runs.
+    ///
+    /// Settings that were not given fall back to the environment, see [`Tools::from_env()`].
+    pub fn tools(&self) -> Tools {
+        let mut tools = Tools::from_env();
+        if let Some(path) = &self.ffmpeg_path {
+            tools.ffmpeg = Some(path.clone());
+        }
+        if let Some(path) = &self.gifsicle_path {
+            tools.gifsicle = Some(path.clone());
+        }
+        tools.temp_dir = self.temp_dir.clone();
+        // `--timeout 0` lifts a timeout given through the environment.
+        if self.timeout.is_some() {
+            tools.timeout = self.timeout();
+        }
+        tools
+    }
+
+    /// Returns the Gifsicle executable given with `--gifsicle-path`.
+    ///
+    /// # Option
+    /// Returns [`None`] if Gifsicle should be looked up as usual.
+    pub fn gifsicle_path(&self) -> Option<&Path> {
+        self.gifsicle_path.as_deref()
+    }
+
+    /// Returns true if the jobs should only be listed.
+    pub fn list_jobs(&self) -> bool {
+        self.list_jobs
+    }
+
+    /// Returns true if GIFs that fail to decode should be repaired.
+    pub fn repair_gif(&self) -> bool {
+        self.repair_gif
+    }
+
+    /// Returns true if the GIF from before optimization should be kept.
+    pub fn keep_unoptimized(&self) -> bool {
+        self.keep_unoptimized
+    }
+
+    /// Returns true if any Gifsicle optimization was requested.
+    pub fn optimizes(&self) -> bool {
+        self.colors().is_some() || self.lossy().is_some() || self.opt_level().is_some()
+    }
+
+    /// Returns the Optimization level of output.
+    ///
+    /// # Option
+    /// Returns [`None`] if no optimization level was specified.
+    pub fn opt_level(&self) -> Option<&str> {
+        self.optimization.as_deref()
+    }
+
+    /// Returns the directory where the output should be saved.
+    ///
+    /// The parent of `--output-file` takes precedence over the output directory.
+    /// If neither was specified, the pictures folder of the user is returned:
+    ///
+    /// - On Unix: `XDG_PICTURES_DIR`, usually `/home/<username>/Pictures`
+    /// - On Windows: `<drive>:\Users\<username>\Pictures`
+    ///
+    /// A pictures folder that does not exist is only returned with `--mkdir`, which
+    /// creates it. See [`default_output_dir()`].
+    ///
+    /// # Errors
+    /// Returns an [`OutputToStdout`] error if `-` is given as the output directory or file,
+    /// only the media can be streamed through stdin.
+    ///
+    /// [`OutputToStdout`]: crate::error::ErrorKind::OutputToStdout
+    pub fn output(&self) -> Result<PathBuf> {
+        let stdout = Some(Path::new("-"));
+        if self.output_directory.as_deref() == stdout || self.output_file.as_deref() == stdout {
+            return Err(ErrorKind::OutputToStdout.into());
+        }
+        if let Some(file) = &self.output_file {
+            return Ok(match file.parent() {
+                Some(parent) if !parent.as_os_str().is_empty() => parent.to_path_buf(),
+                _ => PathBuf::from("."),
+            });
+        }
+        match &self.output_directory {
+            Some(output) => Ok(output.clone()),
+            None => Ok(default_output_dir(self.mkdir)),
+        }
+    }
+
+    /// Returns true if a missing output directory should be created.
+    pub fn mkdir(&self) -> bool {
+        self.mkdir
+    }
+
+    /// Returns true if force overwrite is enabled.
+    pub fn overwrites(&self) -> bool {
+        self.force_overwrite
+    }
+
+    /// Returns the number of colors the output GIF is reduced to.
+    ///
+    /// `--reduce` reduces it to 256 colors.
+    ///
+    /// # Option
+    /// Returns [`None`] if the colors should not be reduced.
+    pub fn colors(&self) -> Option<u16> {
+        if self.reduce {
+            Some(256)
+        } else {
+            self.colors
+        }
+    }
+
+    /// Returns the dithering used when the colors of the output GIF are reduced.
+    ///
+    /// # Option
+    /// Returns [`None`] if Gifsicle should not dither.
+    pub fn dither(&self) -> Option<Dither> {
+        self.dither
+    }
+
+    /// Returns the drop shadow to draw behind the caption text.
+    ///
+    /// # Option
+    /// Returns [`None`] if no shadow offset was given.
+    pub fn shadow(&self) -> Option<Shadow> {
+        self.shadow.map(|offset| Shadow {
+            offset,
+            color: self.shadow_color.unwrap_or(Rgba([0, 0, 0, 160])),
+            blur: self.shadow_blur.filter(|&sigma| sigma > 0.0),
+        })
+    }
+
+    /// Returns the outline to draw around the caption text.
+    ///
+    /// # Option
+    /// Returns [`None`] if no outline color was given or its width is 0.
+    pub fn stroke(&self) -> Option<Stroke> {
+        self.stroke
+            .map(|color| Stroke {
+                color,
+                width: self.stroke_width,
+            })
+            .filter(|stroke| stroke.width > 0)
+    }
+
+    /// Returns true if the media should be passed through without a caption.
+    pub fn no_caption(&self) -> bool {
+        self.no_caption
+    }
+
+    /// Returns the caption text with whitespace trimmed.
+    ///
+    /// Returns an empty string if no caption was given.
+    pub fn text(&self) -> &str {
+        self.caption.as_deref().map_or("", str::trim)
+    }
+
+    /// Returns the caption above the media with whitespace trimmed.
+    ///
+    /// # Option
+    /// Returns [`None`] if no top text was given.
+    pub fn top_text(&self) -> Option<&str> {
+        self.top_text.as_deref().map(str::trim)
+    }
+
+    /// Returns the caption below the media with whitespace trimmed.
+    ///
+    /// # Option
+    /// Returns [`None`] if no bottom text was given.
+    pub fn bottom_text(&self) -> Option<&str> {
+        self.bottom_text.as_deref().map(str::trim)
+    }
+
+    /// Returns the path of the caption script.
+    ///
+    /// # Option
+    /// Returns [`None`] if no caption script was given.
+    pub fn caption_script(&self) -> Option<&Path> {
+        self.caption_script.as_deref()
+    }
+
+    /// Reads the timed captions of the caption script, ordered by their start.
+    ///
+    /// # Errors
+    /// Returns an error if the script cannot be read, [`InvalidCaptionScript`]
+    /// if a line cannot be parsed, [`OverlappingCaptions`] if two captions overlap
+    /// or [`EmptyCaptionScript`] if it has no captions.
+    ///
+    /// # Option
+    /// Returns [`None`] if no caption script was given.
+    ///
+    /// [`InvalidCaptionScript`]: crate::error::ErrorKind::InvalidCaptionScript
+    /// [`OverlappingCaptions`]: crate::error::ErrorKind::OverlappingCaptions
+    /// [`EmptyCaptionScript`]: crate::error::ErrorKind::EmptyCaptionScript
+    pub fn caption_segments(&self) -> Result<Option<Vec<CaptionSegment>>> {
+        let path = match &self.caption_script {
+            Some(path) => path,
+            None => return Ok(None),
+        };
+        let script = fs::read_to_string(path)
+            .context(format!("failed to read caption script: {}", path.display()))?;
+        let segments = parse_caption_script(&script)?;
+        if segments.is_empty() {
+            return Err(ErrorKind::EmptyCaptionScript(path.clone()).into());
+        }
+        Ok(Some(segments))
+    }
+
+    /// Converts the arguments into the [`Job`] captioning the media.
+    ///
+    /// The output name is resolved here, so a random name is only generated once.
+    /// Media piped through stdin must be buffered with [`Cli::buffer_stdin_media()`] first.
+    ///
+    /// # Errors
+    /// Returns an error if [`Cli::media()`], [`Cli::name()`], [`Cli::output()`]
+    /// or [`Cli::caption_segments()`] fail.
+    pub fn job(&self) -> Result<Job> {
+        let (media, media_type) = self.media()?;
+        Ok(Job {
+            media,
+            media_type,
+            output: self.output()?,
+            name: self.name()?,
+            overwrite: self.overwrites(),
+            text: self.text().to_string(),
+            top_text: self.top_text().map(ToOwned::to_owned),
+            bottom_text: self.bottom_text().map(ToOwned::to_owned),
+            caption_segments: self.caption_segments()?,
+            caption_image: self.caption_image.clone(),
+            no_caption: self.no_caption(),
+            position: self.position(),
+            overlay_mode: self.overlay_mode(),
+            overlay_opacity: self.overlay_opacity(),
+            align: self.align(),
+            wrap_mode: self.wrap_mode(),
+            caption_animation: self.caption_animation(),
+            gap: self.gap(),
+            padding: self.padding(),
+            feather: self.feather(),
+            watermark: self.watermark.clone(),
+            watermark_corner: self.watermark_corner(),
+            watermark_opacity: self.watermark_opacity(),
+            gap_color: self.gap_color(),
+            text_color: self.text_color(),
+            bg_color: self.bg_color(),
+            font_scale: self.font_scale(),
+            line_fonts: self.line_fonts.clone(),
+            shadow: self.shadow(),
+            stroke: self.stroke(),
+            opt_level: self.optimization.clone(),
+            lossy: self.lossy(),
+            colors: self.colors(),
+            dither: self.dither(),
+            max_size: self.max_size(),
+            keep_unoptimized: self.keep_unoptimized(),
+            repair_gif: self.repair_gif(),
+            comment: self.comment().map(ToOwned::to_owned),
+            frame_delay: self.frame_delay(),
+            speed: self.speed(),
+            gif_quality: self.gif_quality(),
+            output_format: self.output_format(),
+            loops: self.loops(),
+            loop_video: self.loop_video(),
+            to_gif: self.to_gif(),
+            scale_output: self.scale_output(),
+            encoder_threads: self.encoder_threads(),
+            color_range: self.color_range(),
+            crf: self.crf(),
+            preset: self.preset(),
+            no_audio: self.no_audio(),
+            audio_codec: self.audio_codec.clone(),
+            hwaccel: self.hwaccel(),
+            debug_dump: self.debug_dump.clone(),
+            events: self.event_log.clone(),
+            tools: self.tools(),
+        })
+    }
+
+    /// Returns true if the media gets a top and bottom caption instead of `--caption`.
+    pub fn top_bottom(&self) -> bool {
+        self.top_text.is_some() || self.bottom_text.is_some()
+    }
+
+    /// Returns true if the caption is rendered from text that is empty after trimming.
+    ///
+    /// Captions from `--caption-image` and `--caption-script` are never empty,
+    /// and a top and bottom caption is only empty if both texts are.
+    pub fn caption_is_empty(&self) -> bool {
+        if self.no_caption || self.caption_image.is_some() || self.caption_script.is_some() {
+            return false;
+        }
+        if self.top_bottom() {
+            return [self.top_text(), self.bottom_text()]
+                .into_iter()
+                .flatten()
+                .all(str::is_empty);
+        }
+        self.text().is_empty()
+    }
+}
+
+/// Returns an identifier of the faces loaded from `fonts` for the caption cache.
+///
+/// Every font is identified by a hash of its data, so a font file that was
+/// replaced under the same path does not reuse its old captions.
+/// An empty `fonts` stands for the built-in font.
+pub fn font_id(fonts: &[PathBuf], index: u32, weight: Option<u16>) -> String {
+    let face = match weight {
+        Some(weight) => format!("@{weight}"),
+        None => format!("#{index}"),
+    };
+    if fonts.is_empty() {
+        return format!("ifunny.otf{face}");
+    }
+    let fonts: Vec<_> = fonts
+        .iter()
+        .map(|path| {
+            let mut hasher = FxHasher::default();
+            // a font that cannot be read fails to load before anything is cached.
+            fs::read(path).unwrap_or_default().hash(&mut hasher);
+            format!("{}:{:016x}", path.display(), hasher.finish())
+        })
+        .collect();
+    format!("{}{face}", fonts.join(";"))
+}
+
+/// Returns the pictures folder of the user, the default output directory.
+///
+/// A pictures folder that does not exist is only returned if it will be created
+/// with `create`. Otherwise the first existing folder of [`fallback_output_dir()`] is used.
+pub fn default_output_dir(create: bool) -> PathBuf {
+    match dirs::picture_dir() {
+        Some(path) if path.is_dir() || create => path,
+        _ => {
+            let (name, dir) = fallback_output_dir([
+                ("downloads folder", dirs::download_dir()),
+                ("home folder", dirs::home_dir()),
+            ]);
+            // the output directory is looked up for every job, the notice is logged once.
+            static NOTICE: Once = Once::new();
+            NOTICE.call_once(|| {
+                info!(
+                    "No pictures folder found, writing to the {name} {} instead. Use --mkdir to create the pictures folder.",
+                    dir.display()
+                );
+            });
+            dir
+        }
+    }
+}
+
+/// Returns the extension of the output of media of type `ty`, including the leading dot.
+///
+/// Looping videos are always MP4s, `to_gif` turns videos into GIFs and
+/// GIFs may be written as WebP.
+pub fn output_extension(
+    ty: MediaType,
+    to_gif: bool,
+    loop_video: bool,
+    format: OutputFormat,
+) -> &'static str {
+    if loop_video {
+        MediaType::Mp4.extension()
+    } else if to_gif {
+        MediaType::Gif.extension()
+    } else if ty == MediaType::Gif && format == OutputFormat::Webp {
+        ".webp"
+    } else {
+        ty.extension()
+    }
+}
+
+/// Returns the first of the named `candidates` that is an existing directory.
+///
+/// The current directory is returned if none of them exists.
+pub fn fallback_output_dir<I>(candidates: I) -> (&'static str, PathBuf)
+where
+    I: IntoIterator<Item = (&'static str, Option<PathBuf>)>,
+{
+    candidates
+        .into_iter()
+        .find_map(|(name, dir)| dir.filter(|dir| dir.is_dir()).map(|dir| (name, dir)))
+        .unwrap_or_else(|| ("current directory", PathBuf::from(".")))
+}
+
+/// Returns the file name of `file` if its extension fits output ending in `ext`.
+///
+/// GIF and WebP output must keep their extension. Videos are remuxed by
+/// `FFmpeg`, so they may be written to any supported video container.
+///
+/// # Errors
+/// Returns an [`OutputExtensionMismatch`] error if the extension does not fit.
+///
+/// [`OutputExtensionMismatch`]: crate::error::ErrorKind::OutputExtensionMismatch
+fn output_file_name(file: &Path, ext: &str) -> Result<String> {
+    let name = file
+        .file_name()
+        .context(format!("output file has no name: {}", file.display()))?
+        .to_str()
+        .context(format!(
+            "output file name is not valid utf-8: {}",
+            file.display()
+        ))?;
+    let fits = if ext == MediaType::Gif.extension() || ext == ".webp" {
+        name.to_ascii_lowercase().ends_with(ext)
+    } else {
+        validate_format(file).map_or(false, |ty| ty != MediaType::Gif)
+    };
+    if !fits {
+        return Err(ErrorKind::OutputExtensionMismatch {
+            path: file.to_path_buf(),
+            expected: ext.to_string(),
+        }
+        .into());
+    }
+    Ok(name.to_string())
+}
+
+/// Parses a hex color of the form `#RRGGBB` or `#RRGGBBAA`.
+///
+/// The leading `#` is optional.
+///
+/// # Errors
+/// Returns [`InvalidColor`] if the string is not a valid hex color.
+///
+/// [`InvalidColor`]: crate::error::ErrorKind::InvalidColor
+pub fn parse_color(hex: &str) -> std::result::Result<Rgba<u8>, ErrorKind> {
+    let digits = hex.strip_prefix('#').unwrap_or(hex);
+    if !matches!(digits.len(), 6 | 8) || !digits.chars().all(|c| c.is_ascii_hexdigit()) {
+        return Err(ErrorKind::InvalidColor(hex.to_string()));
+    }
+    let channel = |i: usize| u8::from_str_radix(&digits[i..i + 2], 16).unwrap_or_default();
+    let alpha = if digits.len() == 8 { channel(6) } else { 255 };
+    Ok(Rgba([channel(0), channel(2), channel(4), alpha]))
+}
+
+/// Parses a Gifsicle optimization level, normalizing it to `O1`, `O2` or `O3`.
+///
+/// The level is case-insensitive and may start with a `-`.
+///
+/// # Errors
+/// Returns [`InvalidOptLevel`] if the string is not an optimization level.
+///
+/// [`InvalidOptLevel`]: crate::error::ErrorKind::InvalidOptLevel
+pub fn parse_opt_level(level: &str) -> std::result::Result<String, ErrorKind> {
+    let normalized = level.trim().trim_start_matches('-').to_ascii_uppercase();
+    match normalized.as_str() {
+        "O1" | "O2" | "O3" => Ok(normalized),
+        _ => Err(ErrorKind::InvalidOptLevel(level.to_string())),
+    }
+}
+
+/// Returns the log level for `-v` given `verbose` times, or for `-q`.
+pub fn log_level(verbose: u64, quiet: bool) -> LevelFilter {
+    match (quiet, verbose) {
+        (true, _) => LevelFilter::Warn,
+        (false, 0) => LevelFilter::Info,
+        (false, 1) => LevelFilter::Debug,
+        (false, _) => LevelFilter::Trace,
+    }
+}
+
+/// A caption shown from `start` to `end` seconds into a video.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CaptionSegment {
+    /// Second the caption appears at.
+    pub start: f64,
+    /// Second the caption disappears at.
+    pub end: f64,
+    /// Text of the caption.
+    pub text: String,
+    /// Line of the script the caption was read from, starting at 1.
+    pub line: usize,
+}
+
+/// Parses a caption script of `<start> <end> <text>` lines.
+///
+/// Empty lines and lines starting with `#` are skipped.
+/// The captions are returned ordered by their start.
+///
+/// # Errors
+/// Returns [`InvalidCaptionScript`] if a line cannot be parsed,
+/// or [`OverlappingCaptions`] if two captions are shown at the same time.
+///
+/// [`InvalidCaptionScript`]: crate::error::ErrorKind::InvalidCaptionScript
+/// [`OverlappingCaptions`]: crate::error::ErrorKind::OverlappingCaptions
+pub fn parse_caption_script(script: &str) -> std::result::Result<Vec<CaptionSegment>, ErrorKind> {
+    let mut segments = Vec::new();
+    for (i, line) in script.lines().enumerate() {
+        let line_no = i + 1;
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let invalid = |reason: &str| ErrorKind::InvalidCaptionScript {
+            line: line_no,
+            reason: reason.to_string(),
+        };
+        let mut parts = line.splitn(3, char::is_whitespace);
+        let mut time = || -> std::result::Result<f64, ErrorKind> {
+            parts
+                .next()
+                .and_then(|time| time.parse::<f64>().ok())
+                .filter(|time| time.is_finite() && *time >= 0.0)
+                .ok_or_else(|| invalid("expected <start> <end> <text> with times in seconds"))
+        };
+        let (start, end) = (time()?, time()?);
+        let text = parts.next().map_or("", str::trim);
+        if end <= start {
+            return Err(invalid("the caption ends before it starts"));
+        }
+        if text.is_empty() {
+            return Err(invalid("the caption has no text"));
+        }
+        segments.push(CaptionSegment {
+            start,
+            end,
+            text: text.to_string(),
+            line: line_no,
+        });
+    }
+    segments.sort_by(|a, b| a.start.partial_cmp(&b.start).unwrap_or(Ordering::Equal));
+    for pair in segments.windows(2) {
+        if pair[1].start < pair[0].end {
+            return Err(ErrorKind::OverlappingCaptions {
+                first: pair[0].line,
+                second: pair[1].line,
+            });
+        }
+    }
+    Ok(segments)
+}
+
+/// Parses a color count from 2 to 256.
+///
+/// # Errors
+/// Returns [`InvalidColorCount`] if the string is not a number in that range.
+///
+/// [`InvalidColorCount`]: crate::error::ErrorKind::InvalidColorCount
+pub fn parse_colors(colors: &str) -> std::result::Result<u16, ErrorKind> {
+    match colors.trim().parse() {
+        Ok(value) if (2..=256).contains(&value) => Ok(value),
+        _ => Err(ErrorKind::InvalidColorCount(colors.to_string())),
+    }
+}
+
+/// Parses an opacity from 0 to 1.
+///
+/// # Errors
+/// Returns [`InvalidOpacity`] if the string is not a number in that range.
+///
+/// [`InvalidOpacity`]: crate::error::ErrorKind::InvalidOpacity
+pub fn parse_opacity(opacity: &str) -> std::result::Result<f32, ErrorKind> {
+    match opacity.trim().parse() {
+        Ok(value) if (0.0..=1.0).contains(&value) => Ok(value),
+        _ => Err(ErrorKind::InvalidOpacity(opacity.to_string())),
+    }
+}
+
+/// Parses a GIF encoder speed from 1 to 30.
+///
+/// # Errors
+/// Returns [`InvalidGifQuality`] if the string is not a number in that range.
+///
+/// [`InvalidGifQuality`]: crate::error::ErrorKind::InvalidGifQuality
+pub fn parse_gif_quality(quality: &str) -> std::result::Result<i32, ErrorKind> {
+    match quality.trim().parse() {
+        Ok(value) if (1..=30).contains(&value) => Ok(value),
+        _ => Err(ErrorKind::InvalidGifQuality(quality.to_string())),
+    }
+}
+
+/// Parses a playback speed, a positive number.
+///
+/// # Errors
+/// Returns [`InvalidSpeed`] if the string is not a positive, finite number.
+///
+/// [`InvalidSpeed`]: crate::error::ErrorKind::InvalidSpeed
+pub fn parse_speed(speed: &str) -> std::result::Result<f32, ErrorKind> {
+    match speed.trim().parse::<f32>() {
+        Ok(value) if value.is_finite() && value > 0.0 => Ok(value),
+        _ => Err(ErrorKind::InvalidSpeed(speed.to_string())),
+    }
+}
+
+/// Parses a font weight from 1 to 1000.
+///
+/// # Errors
+/// Returns [`InvalidFontWeight`] if the string is not a number in that range.
+///
+/// [`InvalidFontWeight`]: crate::error::ErrorKind::InvalidFontWeight
+pub fn parse_font_weight(weight: &str) -> std::result::Result<u16, ErrorKind> {
+    match weight.trim().parse() {
+        Ok(value) if (1..=1000).contains(&value) => Ok(value),
+        _ => Err(ErrorKind::InvalidFontWeight(weight.to_string())),
+    }
+}
+
+/// Parses a constant rate factor from 0 to 51.
+///
+/// # Errors
+/// Returns [`InvalidCrf`] if the string is not a number in that range.
+///
+/// [`InvalidCrf`]: crate::error::ErrorKind::InvalidCrf
+pub fn parse_crf(crf: &str) -> std::result::Result<u32, ErrorKind> {
+    match crf.trim().parse() {
+        Ok(value) if value <= 51 => Ok(value),
+        _ => Err(ErrorKind::InvalidCrf(crf.to_string())),
+    }
+}
+
+/// Parses an offset of the form `<dx>,<dy>`.
+///
+/// # Errors
+/// Returns [`InvalidOffset`] if the string is not two comma separated integers.
+///
+/// [`InvalidOffset`]: crate::error::ErrorKind::InvalidOffset
+pub fn parse_offset(offset: &str) -> std::result::Result<(i32, i32), ErrorKind> {
+    let invalid = || ErrorKind::InvalidOffset(offset.to_string());
+    let (dx, dy) = offset.split_once(',').ok_or_else(invalid)?;
+    let dx = dx.trim().parse().map_err(|_| invalid())?;
+    let dy = dy.trim().parse().map_err(|_| invalid())?;
+    Ok((dx, dy))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Returns a directory, a directory next to it and a path that is not a directory.
+    fn paths() -> (PathBuf, PathBuf, PathBuf) {
+        let root = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        (root.join("src"), root.clone(), root.join("Cargo.toml"))
+    }
+
+    #[test]
+    fn fallback_output_dir_takes_the_first_candidate() {
+        let (first, second, _) = paths();
+        assert_eq!(
+            fallback_output_dir([("first", Some(first.clone())), ("second", Some(second))]),
+            ("first", first)
+        );
+    }
+
+    #[test]
+    fn fallback_output_dir_skips_missing_candidates() {
+        let (dir, _, file) = paths();
+        assert_eq!(
+            fallback_output_dir([
+                ("unknown", None),
+                ("file", Some(file)),
+                ("dir", Some(dir.clone()))
+            ]),
+            ("dir", dir)
+        );
+    }
+
+    #[test]
+    fn fallback_output_dir_falls_back_to_the_current_directory() {
+        let (_, _, file) = paths();
+        assert_eq!(
+            fallback_output_dir([("unknown", None), ("file", Some(file))]),
+            ("current directory", PathBuf::from("."))
+        );
+    }
+
+    #[test]
+    fn stdin_media_with_a_suffix_is_not_named_after_the_temporary_file() {
+        let mut cli = Cli::parse_from(["unlustig", "-G", "-", "-T", "caption", "--suffix", "_x"]);
+        let temp = cli.tools().temp_file(".gif");
+        cli.media = temp.path().to_path_buf();
+        cli.stdin_media = Some(temp);
+
+        let name = cli.name().unwrap();
+        let temp_stem = cli.media.file_stem().unwrap().to_str().unwrap();
+        assert!(name.ends_with("_x.gif"), "{name}");
+        assert!(
+            !name.contains(temp_stem),
+            "{name} is named after {temp_stem}"
+        );
+        assert_eq!(name.len(), random_output_name().len() + "_x.gif".len());
+    }
+
+    #[test]
+    fn output_to_stdout_is_rejected() {
+        for flag in ["--output-directory", "--output-file"] {
+            let cli = Cli::parse_from(["unlustig", "-G", "-", "-T", "caption", flag, "-"]);
+            let err = cli.output().unwrap_err();
+            assert!(
+                matches!(err.downcast_ref(), Some(ErrorKind::OutputToStdout)),
+                "{flag}: {err}"
+            );
+        }
+    }
+
+    #[test]
+    fn speed_must_be_a_positive_number() {
+        for speed in ["0", "-1", "nan", "inf", "fast"] {
+            let speed = format!("--speed={speed}");
+            let args = ["unlustig", "-G", "cat.gif", "-T", "caption", &speed];
+            let err = Cli::try_parse_from(args).unwrap_err();
+            assert!(err.to_string().contains("invalid speed"), "{speed}: {err}");
+        }
+        let args = [
+            "unlustig", "-G", "cat.gif", "-T", "caption", "--speed", "0.5",
+        ];
+        assert_eq!(Cli::try_parse_from(args).unwrap().speed(), Some(0.5));
+    }
+
+    #[test]
+    fn mov_media_keeps_its_extension() {
+        for media in ["clip.mov", "IMG_0001.MOV"] {
+            let cli = Cli::parse_from(["unlustig", "-G", media, "-T", "caption", "--suffix", "_x"]);
+            assert_eq!(cli.media().unwrap(), (PathBuf::from(media), MediaType::Mov));
+            let stem = media.rsplit_once('.').unwrap().0;
+            assert_eq!(cli.name().unwrap(), format!("{stem}_x.mov"));
+
+            let cli = Cli::parse_from(["unlustig", "-G", media, "-T", "caption"]);
+            assert!(cli.name().unwrap().ends_with(".mov"));
+        }
     }
 }
@@ -0,0 +1,96 @@
+use std::{
+    fs::File,
+    io::Write,
+    path::Path,
+    sync::Arc,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{Context, Result};
+use log::debug;
+use serde_json::{json, Value};
+
+/// Writes newline-delimited JSON events describing the progress of a run.
+///
+/// Every event is a single JSON object with an `event` name, a unix `time`
+/// and any event specific fields, so UIs can follow a run by tailing the file.
+/// Emitting is a no-op when no event log was opened.
+/// Clones write to the same file.
+#[derive(Debug, Clone, Default)]
+pub struct EventLog {
+    file: Option<Arc<File>>,
+}
+
+impl EventLog {
+    /// Creates an event log writing to `path`.
+    ///
+    /// # Errors
+    /// Returns an error if the file cannot be created.
+    pub fn create(path: &Path) -> Result<Self> {
+        let file = File::create(path)
+            .context(format!("failed to create event log: {}", path.display()))?;
+        Ok(Self {
+            file: Some(Arc::new(file)),
+        })
+    }
+
+    /// Emits an event with the given fields.
+    ///
+    /// `fields` should be a JSON object, anything else is ignored.
+    pub fn emit(&self, event: &str, fields: Value) {
+        let mut file: &File = match &self.file {
+            Some(file) => file,
+            None => return,
+        };
+        let time = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0.0, |time| time.as_secs_f64());
+
+        let mut record = serde_json::Map::new();
+        record.insert("event".into(), event.into());
+        record.insert("time".into(), time.into());
+        if let Value::Object(fields) = fields {
+            record.extend(fields);
+        }
+
+        if let Err(err) = writeln!(file, "{}", Value::Object(record)).and_then(|_| file.flush()) {
+            debug!("failed to write event: {err}");
+        }
+    }
+
+    /// Emits a `stage_started` event.
+    pub fn stage_started(&self, stage: &str) {
+        self.emit("stage_started", json!({ "stage": stage }));
+    }
+
+    /// Emits a `stage_finished` event.
+    pub fn stage_finished(&self, stage: &str) {
+        self.emit("stage_finished", json!({ "stage": stage }));
+    }
+
+    /// Emits a `file_written` event.
+    pub fn file_written(&self, path: &Path) {
+        self.emit(
+            "file_written",
+            json!({ "path": path.display().to_string() }),
+        );
+    }
+
+    /// Emits an `optimized` event with the file size before and after optimization.
+    pub fn optimized(&self, before: u64, after: u64) {
+        let ratio = if before == 0 {
+            1.0
+        } else {
+            after as f64 / before as f64
+        };
+        self.emit(
+            "optimized",
+            json!({ "before": before, "after": after, "ratio": ratio }),
+        );
+    }
+
+    /// Emits an `error` event.
+    pub fn error(&self, err: &anyhow::Error) {
+        self.emit("error", json!({ "message": format!("{err:#}") }));
+    }
+}
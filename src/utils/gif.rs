@@ -1,33 +1,64 @@
 use std::{
     borrow::ToOwned,
-    fs::File,
-
+    fs::{self, File},
+    io::{Read, Seek, SeekFrom},
     path::{Path, PathBuf},
     process::Command,
 };
 
 use anyhow::{Context, Result};
+use human_bytes::human_bytes;
 use image::{
-    codecs::gif::{GifDecoder, GifEncoder},
-    AnimationDecoder, GenericImage, ImageBuffer, ImageDecoder,
+    codecs::gif::{GifDecoder, GifEncoder, Repeat},
+    imageops::FilterType,
+    AnimationDecoder, Delay, Frame, ImageBuffer, ImageDecoder, Rgba,
 };
+use indicatif::{ProgressBar, ProgressStyle};
 use log::{info, warn};
 use rayon::iter::{IntoParallelRefMutIterator, ParallelIterator};
 use rusttype::Font;
 use utils::DepTy;
 use yansi::Paint;
 
+use crate::error::ErrorKind;
 use crate::utils::{
-    self, appdata_init,
-    args::Cli,
-    image::{SetUp, TextImage},
-    random_name,
+    self,
+    args::{CaptionAnimation, CaptionPosition, Dither, OutputFormat, OverlayMode},
+    image::{
+        add_caption, append_gap, caption_layers, caption_lines, caption_strip,
+        check_media_dimensions, check_output_height, draw_watermark, fade, load_watermark,
+        scaled_dimensions, scroll_in, CaptionCache, SetUp, TextImage,
+    },
+    job::Job,
+    process, resolve_output_path, stderr_tail,
+    video::FFmpeg,
+    Outcome, TempFile, Tools,
 };
 
+/// Lossiness and color reduction tried in order when the output has to fit under `--max-size`.
+const SHRINK_LADDER: [(u32, bool); 8] = [
+    (20, false),
+    (40, false),
+    (60, false),
+    (80, false),
+    (80, true),
+    (120, true),
+    (160, true),
+    (200, true),
+];
+
+/// Number of frames that are composited in parallel before they are encoded.
+const FRAME_CHUNK: usize = 32;
+
+/// Shortest frame delay in milliseconds that browsers play as given.
+///
+/// Shorter delays are slowed down to 100ms by most browsers.
+const MIN_FRAME_DELAY_MS: u32 = 20;
 
 /// Contains the path to the [Gifsicle](https://www.lcdf.org/gifsicle/) program.
 pub struct Gifsicle {
     exe: PathBuf,
+    tools: Tools,
 }
 
 impl Gifsicle {
@@ -35,23 +66,55 @@ impl Gifsicle {
     ///
     /// # Errors
     /// * On Windows: Returns an error if the `%appdata%` variable
-    /// is not found
+    ///   is not found
     /// * On Unix: Returns an error if Gifsicle is not installed
-    /// and on the path.
-    pub fn init() -> Result<Self> {
-        let exe = appdata_init(DepTy::Gifsicle)?;
-        Ok(Self { exe })
+    ///   and on the path.
+    pub fn init(tools: &Tools) -> Result<Self> {
+        let exe = tools.exe(DepTy::Gifsicle)?;
+        Ok(Self {
+            exe,
+            tools: tools.clone(),
+        })
     }
 
-    /// Runs `Gifsicle` with specified flags.
+    /// Rewrites a slightly malformed GIF at `input` to `output`.
+    ///
+    /// Gifsicle reads GIFs more leniently than the decoder and
+    /// always writes them to spec.
     ///
     /// # Errors
-    /// Returns an error if Gifsicle fails to spawn.
+    /// Returns an error if Gifsicle fails to spawn or cannot read the GIF.
+    pub fn repair(&self, input: &Path, output: &Path) -> Result<()> {
+        let result = process::output(
+            Command::new(&self.exe)
+                .arg("--no-warnings")
+                .arg(input)
+                .arg("-o")
+                .arg(output),
+            self.tools.timeout,
+        )?;
+        if !result.status.success() {
+            return Err(ErrorKind::GifsicleFailed {
+                status: result.status,
+                stderr: stderr_tail(&result.stderr),
+            })
+            .context(format!("gifsicle could not repair {}", input.display()));
+        }
+        Ok(())
+    }
+
+    /// Runs `Gifsicle` with specified flags and waits for it to finish.
+    ///
+    /// `dither` is only used when `colors` are reduced.
+    ///
+    /// # Errors
+    /// Returns an error if Gifsicle fails to spawn or exits with an error.
     pub fn run(
-        self,
+        &self,
         opt: Option<String>,
         lossy: Option<u32>,
-        reduce: bool,
+        colors: Option<u16>,
+        dither: Option<Dither>,
         imagepath: &Path,
     ) -> Result<()> {
         let mut args = vec![
@@ -66,9 +129,12 @@ impl Gifsicle {
         if let Some(l) = lossy {
             args.push(format!("--lossy={l}"));
         }
-        if reduce {
+        if let Some(colors) = colors {
             args.push("--colors".into());
-            args.push("256".into());
+            args.push(colors.to_string());
+            if let Some(dither) = dither {
+                args.push(format!("--dither={}", dither.as_str()));
+            }
         }
         // No optimization called for.
         if args.len() == 4 {
@@ -76,14 +142,17 @@ impl Gifsicle {
         }
         info!("Optimization is enabled. Optimizing GIF...\nThis might take a while.");
 
-        let mut command = Command::new(self.exe);
-
-        command
-            .args(args)
-            .spawn()
-            .context("failed to start gifsicle")?
-            .wait()
-            .context("gifsicle failed to start")?;
+        let result = process::output(Command::new(&self.exe).args(args), self.tools.timeout)?;
+        if !result.status.success() {
+            return Err(ErrorKind::GifsicleFailed {
+                status: result.status,
+                stderr: stderr_tail(&result.stderr),
+            })
+            .context(format!(
+                "gifsicle failed to optimize {}",
+                imagepath.display()
+            ));
+        }
 
         info!("{}", Paint::green("Optimization complete."));
         Ok(())
@@ -92,60 +161,573 @@ impl Gifsicle {
 
 /// Creates the gifcaption.
 #[allow(clippy::missing_errors_doc)]
-pub fn process_gif(gif: File, font: Font<'static>, cli: &Cli) -> Result<(), anyhow::Error> {
-    let decoder = GifDecoder::new(gif)?;
-    let (gif_w, gif_h) = decoder.dimensions();
-    let init = SetUp::init(font).with_dimensions(gif_w, gif_h);
+pub fn process_gif(
+    mut gif: File,
+    fonts: Vec<Font<'static>>,
+    job: &Job,
+    cache: &mut CaptionCache,
+) -> Result<Outcome, anyhow::Error> {
+    let (input, media_type) = (job.media().to_path_buf(), job.media_type());
+    let events = job.events();
+    let out_path = job.output();
+    let webp = job.output_format() == OutputFormat::Webp;
+    if job.no_caption() {
+        let (output, output_path) = file_and_path(out_path, job.name(), job.overwrites())?;
+        drop(output);
+        let dimensions = image::image_dimensions(&input)?;
+        let optimized = if webp {
+            FFmpeg::init(input.clone(), job.tools())?.gif_to_webp(&output_path, job.loops())?;
+            events.file_written(&output_path);
+            false
+        } else {
+            fs::copy(&input, &output_path)?;
+            if let Some(comment) = job.comment() {
+                write_comment(&output_path, comment)?;
+            }
+            events.file_written(&output_path);
+            optimize(job, &output_path)?
+        };
+        return Ok(Outcome {
+            output: output_path,
+            media_type,
+            dimensions,
+            caption_lines: 0,
+            optimized,
+        });
+    }
+
+    if !has_trailer(&mut gif)? {
+        warn!(
+            "{} appears to be truncated. The output may be missing frames.",
+            input.display()
+        );
+    }
+    let ((gif_w, gif_h), frame_count, repaired) = match scan(gif, &input) {
+        Ok((dimensions, frame_count)) => (dimensions, frame_count, None),
+        Err(err) if job.repair_gif() => {
+            warn!("{err:#}\nRepairing {} with Gifsicle...", input.display());
+            let repaired = job.tools().temp_file(".gif");
+            Gifsicle::init(job.tools())?.repair(&input, repaired.path())?;
+            let (dimensions, frame_count) = scan(File::open(repaired.path())?, &input)
+                .context("failed to decode the repaired GIF")?;
+            (dimensions, frame_count, Some(repaired))
+        }
+        Err(err) => return Err(err.context("use --repair-gif to try repairing the GIF")),
+    };
+    check_media_dimensions(&input, (gif_w, gif_h))?;
+    let source = repaired.as_ref().map_or(input.as_path(), TempFile::path);
+    let init = SetUp::from_job(fonts, job, gif_w, gif_h)?;
+    let caption_lines = caption_lines(job, &init, None);
     info!("Creating caption image...");
-    let image = TextImage::new(init, cli.text()).render()?;
+    events.stage_started("caption");
+    let mut layers = caption_layers(job, &init, cache, job.gap())?.into_iter();
+    let (image, position) = layers.next().context("no caption to render")?;
+    // only top and bottom texts have a second strip, it always goes below the media.
+    let mut bottom = layers.next().map(|(strip, _)| strip);
+    // a caption drawn over the media does not make the output any taller.
+    let caption_height = match job.overlay_mode() {
+        OverlayMode::Pad => image.height() + bottom.as_ref().map_or(0, ImageBuffer::height),
+        OverlayMode::Over => 0,
+    };
+    let dimensions = (gif_w, gif_h + caption_height);
+    check_output_height(dimensions.1)?;
+    let dimensions = job
+        .scale_output()
+        .map_or(dimensions, |width| scaled_dimensions(dimensions, width));
+    utils::dump_image(job.debug_dump(), "caption.png", &image)?;
+    if let Some(bottom) = &bottom {
+        utils::dump_image(job.debug_dump(), "caption-bottom.png", bottom)?;
+    }
 
     info!("{}", Paint::green("Caption image created!"));
-    let mut frames = decoder.into_frames().collect_frames()?;
-    info!("{}", Paint::blue("Rendering GIF..."));
-    frames.par_iter_mut().for_each(|f| {
-        let f = f.buffer_mut();
-        let mut buffer = ImageBuffer::new(gif_w, gif_h + image.height());
-        buffer
-            .copy_from(&image, 0, 0)
-            .expect("could not copy buffer");
-
-        buffer
-            .copy_from(f, 0, image.height())
-            .expect("could not copy buffer");
-
-        *f = buffer;
-    });
-    let out_path = cli.output()?;
-    let (output, output_path) = file_and_path(&out_path, &cli.name()?, cli.overwrites())?;
+    events.stage_finished("caption");
+    let (mut strips, frame_strips) = match job.caption_animation() {
+        Some(animation) => {
+            info!("Animating caption...");
+            let caption = caption_strip(job, &init, cache)?;
+            animate_caption(animation, &init, &caption, frame_count, job, cache)?
+        }
+        None => (vec![image], vec![0; frame_count]),
+    };
+    for strip in strips.iter_mut().chain(bottom.as_mut()) {
+        fade(strip, job.overlay_opacity());
+    }
 
-    let mut encoder = GifEncoder::new_with_speed(&output, 30);
-    encoder.set_repeat(image::codecs::gif::Repeat::Infinite)?;
-    encoder.encode_frames(frames)?;
-    let outputname = &output_path
+    info!("{}", Paint::blue("Rendering GIF..."));
+    events.stage_started("render");
+    let (output, output_path) = file_and_path(out_path, job.name(), job.overwrites())?;
+    if webp {
+        // the frames are encoded as a GIF first, FFmpeg converts that to WebP.
+        drop(output);
+        let frames = job.tools().temp_file(".gif");
+        render_frames(
+            source,
+            &File::create(frames.path())?,
+            &strips,
+            &frame_strips,
+            position,
+            bottom.as_ref(),
+            job,
+        )?;
+        FFmpeg::init(frames.path().to_path_buf(), job.tools())?
+            .gif_to_webp(&output_path, job.loops())?;
+    } else {
+        render_frames(
+            source,
+            &output,
+            &strips,
+            &frame_strips,
+            position,
+            bottom.as_ref(),
+            job,
+        )?;
+        if let Some(comment) = job.comment() {
+            write_comment(&output_path, comment)?;
+        }
+    }
+    events.stage_finished("render");
+    events.file_written(&output_path);
+    let outputname = output_path
         .file_name()
         .context("output path does not exist.")?
-        .to_str()
-        .context("output name is not valid utf-8")?;
+        .to_string_lossy();
 
     info!(
         "{} {outputname} at {}",
         Paint::green("Created"),
-        out_path.to_str().context("output path is not utf-8")?,
+        out_path.display(),
+    );
+
+    let optimized = !webp && optimize(job, &output_path)?;
+    Ok(Outcome {
+        output: output_path,
+        media_type,
+        dimensions,
+        caption_lines,
+        optimized,
+    })
+}
+
+/// Divides a frame delay by the playback `speed`.
+///
+/// Sped up delays are not shortened below [`MIN_FRAME_DELAY_MS`],
+/// unless the frame was already shorter than that.
+fn scale_delay(delay: Delay, speed: f32) -> Delay {
+    let (numer, denom) = delay.numer_denom_ms();
+    let original = f64::from(numer) / f64::from(denom);
+    let ms = (original / f64::from(speed)).max(original.min(f64::from(MIN_FRAME_DELAY_MS)));
+    // kept to the microsecond, it is rounded to what a GIF can store later.
+    Delay::from_numer_denom_ms((ms * 1000.0).round() as u32, 1000)
+}
+
+/// Rounds a frame delay to the nearest 10ms.
+///
+/// GIFs store delays in hundredths of a second and the encoder truncates
+/// anything in between, which made outputs play faster than their source.
+/// The rounding error is kept in `carry` and added to the next frame,
+/// so the whole GIF never drifts by more than half a hundredth of a second.
+fn round_delay(delay: Delay, carry: &mut f64) -> Delay {
+    let (numer, denom) = delay.numer_denom_ms();
+    let ms = f64::from(numer) / f64::from(denom) + *carry;
+    let centis = (ms / 10.0).round().max(0.0);
+    *carry = ms - centis * 10.0;
+    Delay::from_numer_denom_ms(centis as u32 * 10, 1)
+}
+
+/// Reads the dimensions and the duration in seconds of the GIF at `path`.
+///
+/// # Errors
+/// Returns an error if the GIF cannot be decoded.
+pub fn probe(path: &Path) -> Result<((u32, u32), f64)> {
+    let decoder = GifDecoder::new(File::open(path)?)
+        .context(format!("failed to decode {}", path.display()))?;
+    let dimensions = decoder.dimensions();
+    let mut ms = 0.0;
+    for frame in decoder.into_frames() {
+        let (numer, denom) = frame?.delay().numer_denom_ms();
+        ms += f64::from(numer) / f64::from(denom);
+    }
+    Ok((dimensions, ms / 1000.0))
+}
+
+/// Decodes the first frame of the GIF at `path`.
+///
+/// # Errors
+/// Returns an error if the GIF has no frames or cannot be decoded.
+pub fn first_frame(path: &Path) -> Result<ImageBuffer<Rgba<u8>, Vec<u8>>> {
+    let decoder = GifDecoder::new(File::open(path)?)
+        .context(format!("failed to decode {}", path.display()))?;
+    let (width, height) = decoder.dimensions();
+    let frame = decoder
+        .into_frames()
+        .next()
+        .context(format!("{} has no frames", path.display()))?
+        .context(format!(
+            "failed to decode the first frame of {}",
+            path.display()
+        ))?;
+    Ok(coalesce(&mut ImageBuffer::new(width, height), frame).into_buffer())
+}
+
+/// Returns `frame` covering the whole canvas of the GIF.
+///
+/// The decoder already applies the disposal methods and hands out frames
+/// the size of the canvas, but a frame that only updates part of it is drawn
+/// over the previous picture kept in `canvas`, so no frame relies on the
+/// disposal of the one before it once the caption is added.
+fn coalesce(canvas: &mut ImageBuffer<Rgba<u8>, Vec<u8>>, frame: Frame) -> Frame {
+    let (left, top, delay) = (frame.left(), frame.top(), frame.delay());
+    if left == 0 && top == 0 && frame.buffer().dimensions() == canvas.dimensions() {
+        canvas.clone_from(frame.buffer());
+        return frame;
+    }
+    image::imageops::overlay(canvas, frame.buffer(), i64::from(left), i64::from(top));
+    Frame::from_parts(canvas.clone(), 0, 0, delay)
+}
+
+/// Reads the dimensions and the number of frames of a GIF.
+///
+/// Every frame is decoded and dropped right away, so this also checks
+/// that the whole GIF decodes before any output is written.
+/// That decodes the GIF twice, but the frame count is needed up front to lay out
+/// animated captions and size the progress bar, while keeping the frames around
+/// instead would cost memory in proportion to the length of the GIF.
+/// Decoding is cheap next to compositing and encoding every frame again.
+///
+/// # Errors
+/// Returns an error naming the failing frame if the GIF cannot be decoded.
+fn scan(gif: File, input: &Path) -> Result<((u32, u32), usize)> {
+    let decoder = GifDecoder::new(gif).context(format!("failed to decode {}", input.display()))?;
+    let dimensions = decoder.dimensions();
+    let mut frame_count = 0;
+    for (i, frame) in decoder.into_frames().enumerate() {
+        frame.with_context(|| format!("failed to decode frame {i} of {}", input.display()))?;
+        frame_count += 1;
+    }
+    Ok((dimensions, frame_count))
+}
+
+/// Composites the caption onto every frame of the GIF at `source` and encodes them to `output`.
+///
+/// Frames are decoded, composited and encoded [`FRAME_CHUNK`] at a time,
+/// so memory use does not grow with the length of the GIF.
+/// `frame_strips` holds the index into `strips` of every frame, as counted by [`scan`].
+/// The strips go on the `position` side, and `bottom` is added below the media if given.
+/// The watermark is drawn over the captioned frame, before it is scaled.
+///
+/// # Errors
+/// Returns an error if a frame cannot be decoded or encoded.
+fn render_frames(
+    source: &Path,
+    output: &File,
+    strips: &[ImageBuffer<Rgba<u8>, Vec<u8>>],
+    frame_strips: &[usize],
+    position: CaptionPosition,
+    bottom: Option<&ImageBuffer<Rgba<u8>, Vec<u8>>>,
+    job: &Job,
+) -> Result<()> {
+    let delay = job.frame_delay().map(|ms| {
+        if ms < MIN_FRAME_DELAY_MS {
+            warn!("Frame delay {ms}ms is too short for browsers, using {MIN_FRAME_DELAY_MS}ms.");
+        }
+        Delay::from_numer_denom_ms(ms.max(MIN_FRAME_DELAY_MS), 1)
+    });
+    // the bar is shared between the rayon workers, it counts frames atomically.
+    let pb = ProgressBar::new(frame_strips.len() as u64);
+    pb.set_style(
+        ProgressStyle::default_bar()
+            .template("{spinner:.green} [{elapsed_precise}] [{wide_bar:.cyan/blue}] {pos}/{len} frames ({eta})")
+            .progress_chars("#>-"),
     );
 
-    let opt = cli.opt_level().map(ToOwned::to_owned);
-    let lossy = cli.lossy();
-    let reduce = cli.reduce();
-    Gifsicle::init()?.run(opt, lossy, reduce, &output_path)?;
+    let mut encoder = GifEncoder::new_with_speed(output, job.gif_quality());
+    encoder.set_repeat(match job.loops() {
+        0 => Repeat::Infinite,
+        n => Repeat::Finite(n),
+    })?;
+    let decoder = GifDecoder::new(File::open(source)?)
+        .context(format!("failed to decode {}", source.display()))?;
+    let (width, height) = decoder.dimensions();
+    // frames are coalesced in order, as each one may build on the one before.
+    let mut canvas = ImageBuffer::new(width, height);
+    let watermark = job
+        .watermark()
+        .map(|path| load_watermark(path, width, job.watermark_opacity()))
+        .transpose()?;
+    let mut frames = decoder.into_frames().enumerate();
+    let mut chunk = Vec::with_capacity(FRAME_CHUNK);
+    // delays are rounded in order, as the rounding error moves on to the next frame.
+    let mut carry = 0.0;
+    loop {
+        for (i, frame) in frames.by_ref().take(FRAME_CHUNK) {
+            let frame = frame.with_context(|| format!("failed to decode frame {i}"))?;
+            let frame_delay = delay.unwrap_or_else(|| frame.delay());
+            let frame_delay = job
+                .speed()
+                .map_or(frame_delay, |speed| scale_delay(frame_delay, speed));
+            let frame_delay = round_delay(frame_delay, &mut carry);
+            chunk.push((i, frame_delay, coalesce(&mut canvas, frame)));
+        }
+        if chunk.is_empty() {
+            break;
+        }
+        chunk.par_iter_mut().for_each(|(i, delay, f)| {
+            let feather = job.feather().min(f.buffer().height());
+            let mode = job.overlay_mode();
+            let buffer = add_caption(
+                f.buffer(),
+                &strips[frame_strips[*i]],
+                feather,
+                position,
+                mode,
+            );
+            let buffer = match bottom {
+                Some(bottom) => {
+                    add_caption(&buffer, bottom, feather, CaptionPosition::Bottom, mode)
+                }
+                None => buffer,
+            };
+            let buffer = match &watermark {
+                Some(mark) => {
+                    let mut buffer = buffer;
+                    draw_watermark(&mut buffer, mark, job.watermark_corner());
+                    buffer
+                }
+                None => buffer,
+            };
+            let buffer = match job.scale_output() {
+                Some(width) => {
+                    let (width, height) = scaled_dimensions(buffer.dimensions(), width);
+                    image::imageops::resize(&buffer, width, height, FilterType::Triangle)
+                }
+                None => buffer,
+            };
+            // the captioned frame covers the whole output, so it starts at the origin.
+            *f = Frame::from_parts(buffer, 0, 0, *delay);
+            pb.inc(1);
+        });
+        if let Some((0, _, frame)) = chunk.first() {
+            utils::dump_image(job.debug_dump(), "frame0.png", frame.buffer())?;
+        }
+        encoder.encode_frames(chunk.drain(..).map(|(_, _, frame)| frame))?;
+    }
+    pb.finish_and_clear();
+    // the trailer is written when the encoder is dropped.
+    drop(encoder);
     Ok(())
 }
 
+/// The distinct caption strips of a GIF, and the index of the strip used by each frame.
+type FrameStrips = (Vec<ImageBuffer<Rgba<u8>, Vec<u8>>>, Vec<usize>);
+
+/// Renders the caption strip of every frame of an animated caption.
+///
+/// The caption is revealed over the first half of the frames and then held.
+/// Returns the distinct strips, and the index of the strip used by each frame.
+///
+/// # Errors
+/// Returns an error if a partially revealed caption fails to render.
+fn animate_caption(
+    animation: CaptionAnimation,
+    init: &SetUp,
+    caption: &ImageBuffer<Rgba<u8>, Vec<u8>>,
+    frame_count: usize,
+    job: &Job,
+    cache: &mut CaptionCache,
+) -> Result<FrameStrips> {
+    let reveal_frames = (frame_count / 2).max(1);
+    let total_chars = TextImage::new(init.clone(), job.text()).char_count();
+    let mut strips = Vec::new();
+    let mut frame_strips = Vec::with_capacity(frame_count);
+    let mut last_step = None;
+
+    for i in 0..frame_count {
+        let progress = (i + 1).min(reveal_frames);
+        // frames that show the same state share a strip.
+        let step = match animation {
+            CaptionAnimation::Typewriter => total_chars * progress / reveal_frames,
+            CaptionAnimation::Scrollup => progress,
+        };
+        if last_step != Some(step) {
+            let strip = match animation {
+                CaptionAnimation::Typewriter => {
+                    cache.render(TextImage::new(init.clone(), job.text()).with_reveal(step))?
+                }
+                CaptionAnimation::Scrollup => scroll_in(
+                    caption,
+                    progress as f32 / reveal_frames as f32,
+                    init.bg_color(),
+                ),
+            };
+            strips.push(append_gap(
+                strip,
+                job.gap(),
+                job.gap_color(),
+                job.position(),
+            ));
+            last_step = Some(step);
+        }
+        frame_strips.push(strips.len() - 1);
+    }
+    Ok((strips, frame_strips))
+}
+
+/// Finishes a GIF written by `FFmpeg` like one written by [`process_gif`].
+///
+/// Embeds the comment and runs the requested Gifsicle optimizations.
+/// Returns true if Gifsicle optimized the GIF.
+///
+/// # Errors
+/// Returns an error if the comment cannot be written or Gifsicle fails.
+pub fn finish_gif(job: &Job, path: &Path) -> Result<bool> {
+    if let Some(comment) = job.comment() {
+        write_comment(path, comment)?;
+    }
+    optimize(job, path)
+}
+
+/// Runs the requested Gifsicle optimizations on the output GIF.
+///
+/// Does nothing if no optimization or size limit was requested.
+/// Returns true if Gifsicle ran.
+///
+/// # Errors
+/// Returns an error if Gifsicle cannot be found or fails.
+fn optimize(job: &Job, output_path: &Path) -> Result<bool> {
+    if !job.optimizes() && job.max_size().is_none() {
+        return Ok(false);
+    }
+    let events = job.events();
+    let opt = job.opt_level().map(ToOwned::to_owned);
+    let lossy = job.lossy();
+    let colors = job.colors();
+
+    events.stage_started("optimize");
+    let gifsicle = Gifsicle::init(job.tools())?;
+    let before = fs::metadata(output_path)?.len();
+    let unoptimized = if job.keep_unoptimized() {
+        let copy = unoptimized_path(output_path)?;
+        fs::copy(output_path, &copy).context(format!(
+            "failed to keep the unoptimized GIF: {}",
+            copy.display()
+        ))?;
+        events.file_written(&copy);
+        Some(copy)
+    } else {
+        None
+    };
+    gifsicle.run(opt, lossy, colors, job.dither(), output_path)?;
+    if let Some(max_size) = job.max_size() {
+        shrink(&gifsicle, output_path, max_size, job.dither())?;
+    }
+    let after = fs::metadata(output_path)?.len();
+    events.optimized(before, after);
+    events.stage_finished("optimize");
+    if let Some(copy) = unoptimized {
+        info!(
+            "Unoptimized: {} ({}), optimized: {} ({})",
+            copy.display(),
+            human_bytes(before as f64),
+            output_path.display(),
+            human_bytes(after as f64),
+        );
+    }
+    Ok(true)
+}
+
+/// Re-optimizes the GIF with increasingly lossy settings until it is at most `max_size` bytes.
+///
+/// Every attempt starts from a copy of the GIF as it was before shrinking.
+/// Gives up with a warning once every setting was tried.
+fn shrink(gifsicle: &Gifsicle, gif: &Path, max_size: u64, dither: Option<Dither>) -> Result<()> {
+    let mut size = fs::metadata(gif)?.len();
+    if size <= max_size {
+        return Ok(());
+    }
+    let original = gifsicle.tools.temp_file(".gif");
+    fs::copy(gif, original.path())?;
+
+    let max = human_bytes(max_size as f64);
+    for (lossy, reduce) in SHRINK_LADDER {
+        info!(
+            "Output is {}, which is over {max}. Retrying with --lossy={lossy}{}...",
+            human_bytes(size as f64),
+            if reduce { " --colors 256" } else { "" }
+        );
+        fs::copy(original.path(), gif)?;
+        let colors = if reduce { Some(256) } else { None };
+        gifsicle.run(Some("O3".into()), Some(lossy), colors, dither, gif)?;
+
+        size = fs::metadata(gif)?.len();
+        if size <= max_size {
+            info!(
+                "{} Final settings: -O3 --lossy={lossy}{} ({})",
+                Paint::green("Output fits."),
+                if reduce { " --colors 256" } else { "" },
+                human_bytes(size as f64)
+            );
+            return Ok(());
+        }
+    }
+    warn!(
+        "Could not shrink the output under {max}, it is {}.",
+        human_bytes(size as f64)
+    );
+    Ok(())
+}
+
+/// Returns true if the GIF ends with the trailer byte (`0x3B`).
+///
+/// Truncated files decode without errors up to their last complete frame,
+/// so a missing trailer is the only hint that frames were lost.
+fn has_trailer(gif: &mut File) -> Result<bool> {
+    let mut last = [0];
+    gif.seek(SeekFrom::End(-1))
+        .and_then(|_| gif.read_exact(&mut last))
+        .and_then(|_| gif.seek(SeekFrom::Start(0)))
+        .context("failed to read the end of the GIF")?;
+    Ok(last[0] == 0x3B)
+}
+
+/// Adds a comment extension with `comment` to the end of the GIF at `path`.
+///
+/// The comment is inserted right before the trailer. GIFs without
+/// a trailer are left as they are.
+///
+/// # Errors
+/// Returns an error if the GIF cannot be read or written.
+fn write_comment(path: &Path, comment: &str) -> Result<()> {
+    let mut gif = fs::read(path).context(format!("failed to read {}", path.display()))?;
+    if gif.pop() != Some(0x3B) {
+        warn!("{} has no trailer, not adding the comment.", path.display());
+        return Ok(());
+    }
+    gif.extend([0x21, 0xFE]);
+    for block in comment.as_bytes().chunks(255) {
+        gif.push(block.len() as u8);
+        gif.extend(block);
+    }
+    gif.extend([0x00, 0x3B]);
+    fs::write(path, gif).context(format!("failed to write {}", path.display()))
+}
+
+/// Returns the path the unoptimized copy of `output_path` is kept at.
+///
+/// `out.gif` is kept as `out-unoptimized.gif`.
+fn unoptimized_path(output_path: &Path) -> Result<PathBuf> {
+    // the stem is kept as is, names that are not utf-8 still get a matching copy.
+    let mut name = output_path
+        .file_stem()
+        .context("output path has no file name")?
+        .to_owned();
+    name.push("-unoptimized.gif");
+    Ok(output_path.with_file_name(name))
+}
+
 /// Returns the File and the path of the file.
 ///
 /// This takes into account if the overwrite flag was enabled.
-///
-/// If overwrite was disabled, custom name specified, and a file with a similar name was found,
-/// prepends a random name using [`random_name()`].
+/// See also: [`resolve_output_path()`]
 ///
 /// # Errors
 /// Returns an error if the file creation fails.
@@ -154,24 +736,230 @@ fn file_and_path(
     name: &str,
     overwrite: bool,
 ) -> Result<(File, PathBuf), anyhow::Error> {
-    let default = |file_name: &str| -> Result<(File, PathBuf), anyhow::Error> {
-        Ok((
-            File::create(&out_path.join(&file_name))?,
-            out_path.join(file_name),
-        ))
-    };
+    let output_path = resolve_output_path(out_path, name, overwrite);
+    Ok((File::create(&output_path)?, output_path))
+}
 
-    let (output, output_path) = if out_path.join(&name).exists() {
-        if overwrite {
-            info!("Overwrite is enabled. Any file with the same name ({}) will be overwritten by the output file", &name);
-            default(name)?
-        } else {
-            warn!("Overwrite is disabled. File with a similar name found. Modifying name.");
-            let bind = format!("{}_{}", random_name(), &name);
-            default(&bind)?
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+
+    /// Encodes 1x1 frames with the given delays and returns the delays read back in ms.
+    fn encoded_delays(delays: &[Delay]) -> Vec<f64> {
+        let mut gif = Vec::new();
+        {
+            let mut encoder = GifEncoder::new(&mut gif);
+            let frames = delays.iter().map(|&delay| {
+                Frame::from_parts(
+                    ImageBuffer::from_pixel(1, 1, Rgba([0, 0, 0, 255])),
+                    0,
+                    0,
+                    delay,
+                )
+            });
+            encoder.encode_frames(frames).unwrap();
         }
-    } else {
-        default(name)?
-    };
-    Ok((output, output_path))
+        GifDecoder::new(Cursor::new(gif))
+            .unwrap()
+            .into_frames()
+            .map(|frame| {
+                let (numer, denom) = frame.unwrap().delay().numer_denom_ms();
+                f64::from(numer) / f64::from(denom)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn speed_divides_the_frame_delay() {
+        let ms = |delay: Delay| {
+            let (numer, denom) = delay.numer_denom_ms();
+            f64::from(numer) / f64::from(denom)
+        };
+        let delay = Delay::from_numer_denom_ms(100, 1);
+        assert!((ms(scale_delay(delay, 2.0)) - 50.0).abs() < 0.01);
+        assert!((ms(scale_delay(delay, 0.5)) - 200.0).abs() < 0.01);
+        // sped up delays stop at the shortest delay browsers play as given.
+        assert!((ms(scale_delay(delay, 10.0)) - f64::from(MIN_FRAME_DELAY_MS)).abs() < 0.01);
+    }
+
+    #[test]
+    fn reencoded_delays_add_up_to_the_source() {
+        let source: Vec<_> = [30, 40, 30, 70, 30, 50, 30, 90]
+            .iter()
+            .cycle()
+            .take(200)
+            .map(|&ms| Delay::from_numer_denom_ms(ms, 1))
+            .collect();
+        let source_ms = encoded_delays(&source);
+        for speed in [1.0, 1.3, 1.5, 2.0, 3.0] {
+            let mut carry = 0.0;
+            let output: Vec<_> = source
+                .iter()
+                .map(|&delay| round_delay(scale_delay(delay, speed), &mut carry))
+                .collect();
+            let expected: f64 = source_ms
+                .iter()
+                .map(|ms| (ms / f64::from(speed)).max(ms.min(f64::from(MIN_FRAME_DELAY_MS))))
+                .sum();
+            let actual: f64 = encoded_delays(&output).iter().sum();
+            // a GIF stores delays in ticks of 10ms.
+            assert!(
+                (actual - expected).abs() <= 10.0,
+                "{actual}ms at {speed}x, expected {expected}ms"
+            );
+        }
+    }
+
+    /// Encodes a 4x4 GIF that starts out transparent and is then drawn on in parts.
+    ///
+    /// Red covers the top left quarter, then blue the bottom right quarter with a
+    /// transparent hole in its corner, then blue the top right pixel.
+    fn partial_gif() -> Vec<u8> {
+        let palette = [0, 0, 0, 255, 0, 0, 0, 0, 255];
+        let mut gif = Vec::new();
+        {
+            let mut encoder = gif::Encoder::new(&mut gif, 4, 4, &palette).unwrap();
+            let parts: [(u16, u16, u16, u16, &[u8]); 3] = [
+                (0, 0, 2, 2, &[1, 1, 1, 1]),
+                (2, 2, 2, 2, &[0, 2, 2, 2]),
+                (3, 0, 1, 1, &[2]),
+            ];
+            for (left, top, width, height, indices) in parts {
+                encoder
+                    .write_frame(&gif::Frame {
+                        left,
+                        top,
+                        width,
+                        height,
+                        buffer: indices.into(),
+                        transparent: Some(0),
+                        dispose: gif::DisposalMethod::Keep,
+                        delay: 10,
+                        ..gif::Frame::default()
+                    })
+                    .unwrap();
+            }
+        }
+        gif
+    }
+
+    #[test]
+    fn partial_frames_are_coalesced_onto_the_full_canvas() {
+        let (transparent, red, blue) = (
+            Rgba([0, 0, 0, 0]),
+            Rgba([255, 0, 0, 255]),
+            Rgba([0, 0, 255, 255]),
+        );
+        let expected = |i: usize, x: u32, y: u32| match (x, y) {
+            (0..=1, 0..=1) => red,
+            (2, 2) => transparent,
+            (2..=3, 2..=3) if i >= 1 => blue,
+            (3, 0) if i >= 2 => blue,
+            _ => transparent,
+        };
+        let strip = ImageBuffer::from_pixel(4, 2, Rgba([255, 255, 255, 255]));
+
+        let decoder = GifDecoder::new(Cursor::new(partial_gif())).unwrap();
+        let mut canvas = ImageBuffer::new(4, 4);
+        for (i, frame) in decoder.into_frames().enumerate() {
+            let frame = coalesce(&mut canvas, frame.unwrap());
+            assert_eq!((frame.left(), frame.top()), (0, 0));
+            assert_eq!(frame.buffer().dimensions(), (4, 4));
+            for (x, y, pixel) in frame.buffer().enumerate_pixels() {
+                assert_eq!(*pixel, expected(i, x, y), "frame {i} at ({x}, {y})");
+            }
+
+            // nothing from an earlier frame shows through the caption.
+            let captioned = add_caption(
+                frame.buffer(),
+                &strip,
+                0,
+                CaptionPosition::Top,
+                OverlayMode::Pad,
+            );
+            assert_eq!(captioned.dimensions(), (4, 6));
+            for (x, y, pixel) in captioned.enumerate_pixels() {
+                if y < 2 {
+                    assert_eq!(pixel, strip.get_pixel(x, y), "frame {i} at ({x}, {y})");
+                } else {
+                    assert_eq!(*pixel, expected(i, x, y - 2), "frame {i} at ({x}, {y})");
+                }
+            }
+        }
+    }
+
+    /// Returns the peak resident memory of the process in bytes.
+    #[cfg(target_os = "linux")]
+    fn peak_memory() -> u64 {
+        let status = fs::read_to_string("/proc/self/status").unwrap();
+        let kib = status
+            .lines()
+            .find_map(|line| line.strip_prefix("VmHWM:"))
+            .and_then(|kib| kib.trim().trim_end_matches("kB").trim().parse::<u64>().ok())
+            .unwrap();
+        kib * 1024
+    }
+
+    /// Encodes a GIF of `frames` gradient frames of `width`x`height` to `path`.
+    #[cfg(target_os = "linux")]
+    fn write_gif(path: &Path, frames: u32, (width, height): (u32, u32)) {
+        let mut encoder = GifEncoder::new_with_speed(File::create(path).unwrap(), 30);
+        for i in 0..frames {
+            let frame = ImageBuffer::from_fn(width, height, |x, y| {
+                Rgba([((x + i) % 256) as u8, (y % 256) as u8, (i % 256) as u8, 255])
+            });
+            let delay = Delay::from_numer_denom_ms(40, 1);
+            encoder
+                .encode_frame(Frame::from_parts(frame, 0, 0, delay))
+                .unwrap();
+        }
+    }
+
+    // run with `cargo test --release -- --ignored`, peak memory is only meaningful on its own.
+    #[cfg(target_os = "linux")]
+    #[test]
+    #[ignore]
+    fn render_frames_memory_does_not_grow_with_the_frame_count() {
+        const FRAMES: u32 = 50;
+        let dimensions = (480, 270);
+        let tools = Tools::default();
+        let (short, long) = (tools.temp_file(".gif"), tools.temp_file(".gif"));
+        write_gif(short.path(), FRAMES, dimensions);
+        write_gif(long.path(), 4 * FRAMES, dimensions);
+
+        let strips = [ImageBuffer::from_pixel(
+            dimensions.0,
+            60,
+            Rgba([255, 255, 255, 255]),
+        )];
+        let render = |source: &TempFile| {
+            let job = Job::new(source.path(), "caption").unwrap();
+            let output = tools.temp_file(".gif");
+            let (_, frame_count) = scan(File::open(source.path()).unwrap(), source.path()).unwrap();
+            render_frames(
+                source.path(),
+                &File::create(output.path()).unwrap(),
+                &strips,
+                &vec![0; frame_count],
+                CaptionPosition::Top,
+                None,
+                &job,
+            )
+            .unwrap();
+            peak_memory()
+        };
+        let (short_peak, long_peak) = (render(&short), render(&long));
+
+        // holding the extra frames would take this much more memory.
+        let frame_bytes = u64::from(dimensions.0 * (dimensions.1 + 60) * 4);
+        let held = u64::from(3 * FRAMES) * frame_bytes;
+        let growth = long_peak.saturating_sub(short_peak);
+        assert!(
+            growth < held / 4,
+            "peak memory grew by {growth} bytes from {FRAMES} to {} frames",
+            4 * FRAMES
+        );
+    }
 }
@@ -0,0 +1,580 @@
+use std::path::{Path, PathBuf};
+
+use anyhow::Result;
+use image::Rgba;
+
+use crate::utils::{
+    args::{
+        default_output_dir, output_extension, parse_opt_level, CaptionAnimation, CaptionPosition,
+        CaptionSegment, ColorRange, Corner, Dither, HwAccel, OutputFormat, OverlayMode, Preset,
+        TextAlign, WrapMode,
+    },
+    events::EventLog,
+    image::{Shadow, Stroke},
+    random_output_name, validate_format, MediaType, Tools,
+};
+
+/// Everything a single captioning job needs, resolved from its arguments.
+///
+/// [`process_gif()`] and [`FFmpeg::process_media()`] take a job instead of the
+/// arguments, so the media can be captioned without parsing a command line.
+/// [`Cli::job()`] converts the arguments, [`Job::new()`] starts from the defaults
+/// of the command line.
+///
+/// [`process_gif()`]: crate::utils::gif::process_gif
+/// [`FFmpeg::process_media()`]: crate::utils::video::FFmpeg::process_media
+/// [`Cli::job()`]: crate::utils::args::Cli::job
+#[derive(Debug, Clone)]
+pub struct Job {
+    pub(crate) media: PathBuf,
+    pub(crate) media_type: MediaType,
+    pub(crate) output: PathBuf,
+    pub(crate) name: String,
+    pub(crate) overwrite: bool,
+    pub(crate) text: String,
+    pub(crate) top_text: Option<String>,
+    pub(crate) bottom_text: Option<String>,
+    pub(crate) caption_segments: Option<Vec<CaptionSegment>>,
+    pub(crate) caption_image: Option<PathBuf>,
+    pub(crate) no_caption: bool,
+    pub(crate) position: CaptionPosition,
+    pub(crate) overlay_mode: OverlayMode,
+    pub(crate) overlay_opacity: f32,
+    pub(crate) align: TextAlign,
+    pub(crate) wrap_mode: WrapMode,
+    pub(crate) caption_animation: Option<CaptionAnimation>,
+    pub(crate) gap: u32,
+    pub(crate) padding: Option<u32>,
+    pub(crate) feather: u32,
+    pub(crate) watermark: Option<PathBuf>,
+    pub(crate) watermark_corner: Corner,
+    pub(crate) watermark_opacity: f32,
+    pub(crate) gap_color: Rgba<u8>,
+    pub(crate) text_color: Rgba<u8>,
+    pub(crate) bg_color: Rgba<u8>,
+    pub(crate) font_scale: f32,
+    pub(crate) line_fonts: Vec<usize>,
+    pub(crate) shadow: Option<Shadow>,
+    pub(crate) stroke: Option<Stroke>,
+    pub(crate) opt_level: Option<String>,
+    pub(crate) lossy: Option<u32>,
+    pub(crate) colors: Option<u16>,
+    pub(crate) dither: Option<Dither>,
+    pub(crate) max_size: Option<u64>,
+    pub(crate) keep_unoptimized: bool,
+    pub(crate) repair_gif: bool,
+    pub(crate) comment: Option<String>,
+    pub(crate) frame_delay: Option<u32>,
+    pub(crate) speed: Option<f32>,
+    pub(crate) gif_quality: i32,
+    pub(crate) output_format: OutputFormat,
+    pub(crate) loops: u16,
+    pub(crate) loop_video: Option<u32>,
+    pub(crate) to_gif: bool,
+    pub(crate) scale_output: Option<u32>,
+    pub(crate) encoder_threads: Option<u32>,
+    pub(crate) color_range: Option<ColorRange>,
+    pub(crate) crf: Option<u32>,
+    pub(crate) preset: Option<Preset>,
+    pub(crate) no_audio: bool,
+    pub(crate) audio_codec: Option<String>,
+    pub(crate) hwaccel: Option<HwAccel>,
+    pub(crate) debug_dump: Option<PathBuf>,
+    pub(crate) events: EventLog,
+    pub(crate) tools: Tools,
+}
+
+impl Job {
+    /// Creates a job captioning `media` with `text`, using the defaults of the command line.
+    ///
+    /// The output gets a random name in the pictures folder of the user,
+    /// and the external programs are set up from the environment, see [`Tools::from_env()`].
+    ///
+    /// # Errors
+    /// Returns an [`UnsupportedMediaFormat`] error if the media is unsupported.
+    ///
+    /// [`UnsupportedMediaFormat`]: crate::error::ErrorKind::UnsupportedMediaFormat
+    pub fn new(media: impl Into<PathBuf>, text: &str) -> Result<Self> {
+        let media = media.into();
+        let media_type = validate_format(&media)?;
+        let ext = output_extension(media_type, false, false, OutputFormat::Gif);
+        Ok(Self {
+            media,
+            media_type,
+            output: default_output_dir(false),
+            name: format!("{}{ext}", random_output_name()),
+            overwrite: false,
+            text: text.trim().to_string(),
+            top_text: None,
+            bottom_text: None,
+            caption_segments: None,
+            caption_image: None,
+            no_caption: false,
+            position: CaptionPosition::Top,
+            overlay_mode: OverlayMode::Pad,
+            overlay_opacity: 1.0,
+            align: TextAlign::Center,
+            wrap_mode: WrapMode::Auto,
+            caption_animation: None,
+            gap: 0,
+            padding: None,
+            feather: 0,
+            watermark: None,
+            watermark_corner: Corner::BottomRight,
+            watermark_opacity: 1.0,
+            gap_color: Rgba([255, 255, 255, 255]),
+            text_color: Rgba([0, 0, 0, 255]),
+            bg_color: Rgba([255, 255, 255, 255]),
+            font_scale: 1.0,
+            line_fonts: Vec::new(),
+            shadow: None,
+            stroke: None,
+            opt_level: None,
+            lossy: None,
+            colors: None,
+            dither: None,
+            max_size: None,
+            keep_unoptimized: false,
+            repair_gif: false,
+            comment: None,
+            frame_delay: None,
+            speed: None,
+            gif_quality: 30,
+            output_format: OutputFormat::Gif,
+            loops: 0,
+            loop_video: None,
+            to_gif: false,
+            scale_output: None,
+            encoder_threads: None,
+            color_range: None,
+            crf: None,
+            preset: None,
+            no_audio: false,
+            audio_codec: None,
+            hwaccel: None,
+            debug_dump: None,
+            events: EventLog::default(),
+            tools: Tools::from_env(),
+        })
+    }
+
+    /// Sets the directory the output is written to.
+    pub fn with_output(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.output = dir.into();
+        self
+    }
+
+    /// Sets the name of the output file.
+    ///
+    /// The extension of the output is appended unless the name already contains it.
+    pub fn with_name(mut self, name: &str) -> Self {
+        let ext = output_extension(self.media_type, self.to_gif, false, self.output_format);
+        self.name = if name.contains(ext) {
+            name.to_string()
+        } else {
+            format!("{name}{ext}")
+        };
+        self
+    }
+
+    /// Sets whether an existing output file is overwritten.
+    pub fn with_overwrite(mut self, overwrite: bool) -> Self {
+        self.overwrite = overwrite;
+        self
+    }
+
+    /// Sets the side of the media the caption is placed on.
+    pub fn with_position(mut self, position: CaptionPosition) -> Self {
+        self.position = position;
+        self
+    }
+
+    /// Sets the Gifsicle optimization level, e.g. `O2`.
+    ///
+    /// # Errors
+    /// Returns [`InvalidOptLevel`] if the string is not an optimization level.
+    ///
+    /// [`InvalidOptLevel`]: crate::error::ErrorKind::InvalidOptLevel
+    pub fn with_opt_level(mut self, level: &str) -> Result<Self> {
+        self.opt_level = Some(parse_opt_level(level)?);
+        Ok(self)
+    }
+
+    /// Sets the settings of the external programs the job runs.
+    pub fn with_tools(mut self, tools: Tools) -> Self {
+        self.tools = tools;
+        self
+    }
+
+    /// Returns the path of the input media.
+    pub fn media(&self) -> &Path {
+        &self.media
+    }
+
+    /// Returns the type of the input media.
+    pub fn media_type(&self) -> MediaType {
+        self.media_type
+    }
+
+    /// Returns the directory the output is written to.
+    pub fn output(&self) -> &Path {
+        &self.output
+    }
+
+    /// Returns the name of the output media.
+    ///
+    /// The name is resolved once, so a random name stays the same for the whole job.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Returns true if an existing output file is overwritten.
+    pub fn overwrites(&self) -> bool {
+        self.overwrite
+    }
+
+    /// Returns the caption text with whitespace trimmed.
+    pub fn text(&self) -> &str {
+        &self.text
+    }
+
+    /// Returns the caption above the media with whitespace trimmed.
+    ///
+    /// # Option
+    /// Returns [`None`] if no top text was given.
+    pub fn top_text(&self) -> Option<&str> {
+        self.top_text.as_deref()
+    }
+
+    /// Returns the caption below the media with whitespace trimmed.
+    ///
+    /// # Option
+    /// Returns [`None`] if no bottom text was given.
+    pub fn bottom_text(&self) -> Option<&str> {
+        self.bottom_text.as_deref()
+    }
+
+    /// Returns true if the media gets a top and bottom caption instead of the caption text.
+    pub fn top_bottom(&self) -> bool {
+        self.top_text.is_some() || self.bottom_text.is_some()
+    }
+
+    /// Returns the timed captions of the caption script, ordered by their start.
+    ///
+    /// # Option
+    /// Returns [`None`] if no caption script was given.
+    pub fn caption_segments(&self) -> Option<&[CaptionSegment]> {
+        self.caption_segments.as_deref()
+    }
+
+    /// Returns the path of the pre-rendered caption image.
+    ///
+    /// # Option
+    /// Returns [`None`] if the caption should be rendered from text.
+    pub fn caption_image(&self) -> Option<&Path> {
+        self.caption_image.as_deref()
+    }
+
+    /// Returns true if the media should be passed through without a caption.
+    pub fn no_caption(&self) -> bool {
+        self.no_caption
+    }
+
+    /// Returns the side of the media the caption is placed on.
+    pub fn position(&self) -> CaptionPosition {
+        self.position
+    }
+
+    /// Returns whether the caption is added next to the media or drawn over it.
+    pub fn overlay_mode(&self) -> OverlayMode {
+        self.overlay_mode
+    }
+
+    /// Returns the opacity of the caption, from 0 to 1.
+    pub fn overlay_opacity(&self) -> f32 {
+        self.overlay_opacity
+    }
+
+    /// Returns the alignment of multi-line captions.
+    pub fn align(&self) -> TextAlign {
+        self.align
+    }
+
+    /// Returns where long captions may be broken into lines.
+    pub fn wrap_mode(&self) -> WrapMode {
+        self.wrap_mode
+    }
+
+    /// Returns the animation revealing the caption.
+    ///
+    /// # Option
+    /// Returns [`None`] if the caption is not animated.
+    pub fn caption_animation(&self) -> Option<CaptionAnimation> {
+        self.caption_animation
+    }
+
+    /// Returns the height of the gap between the caption and the media.
+    pub fn gap(&self) -> u32 {
+        self.gap
+    }
+
+    /// Returns the whitespace above and below the caption text.
+    ///
+    /// # Option
+    /// Returns [`None`] if the padding should be derived from the text size.
+    pub fn padding(&self) -> Option<u32> {
+        self.padding
+    }
+
+    /// Returns the height of the fade between the caption and the media.
+    pub fn feather(&self) -> u32 {
+        self.feather
+    }
+
+    /// Returns the path of the watermark image.
+    ///
+    /// # Option
+    /// Returns [`None`] if no watermark was given.
+    pub fn watermark(&self) -> Option<&Path> {
+        self.watermark.as_deref()
+    }
+
+    /// Returns the corner the watermark is drawn in.
+    pub fn watermark_corner(&self) -> Corner {
+        self.watermark_corner
+    }
+
+    /// Returns the opacity of the watermark, from 0 to 1.
+    pub fn watermark_opacity(&self) -> f32 {
+        self.watermark_opacity
+    }
+
+    /// Returns the color of the gap between the caption and the media.
+    pub fn gap_color(&self) -> Rgba<u8> {
+        self.gap_color
+    }
+
+    /// Returns the color of the caption text.
+    pub fn text_color(&self) -> Rgba<u8> {
+        self.text_color
+    }
+
+    /// Returns the color of the caption background.
+    pub fn bg_color(&self) -> Rgba<u8> {
+        self.bg_color
+    }
+
+    /// Returns the factor the caption's text size is multiplied by.
+    pub fn font_scale(&self) -> f32 {
+        self.font_scale
+    }
+
+    /// Returns the index of the font for every wrapped line.
+    pub fn line_fonts(&self) -> &[usize] {
+        &self.line_fonts
+    }
+
+    /// Returns the drop shadow to draw behind the caption text.
+    ///
+    /// # Option
+    /// Returns [`None`] if the text has no shadow.
+    pub fn shadow(&self) -> Option<Shadow> {
+        self.shadow
+    }
+
+    /// Returns the outline to draw around the caption text.
+    ///
+    /// # Option
+    /// Returns [`None`] if the text has no outline.
+    pub fn stroke(&self) -> Option<Stroke> {
+        self.stroke
+    }
+
+    /// Returns true if any Gifsicle optimization was requested.
+    pub fn optimizes(&self) -> bool {
+        self.colors.is_some() || self.lossy.is_some() || self.opt_level.is_some()
+    }
+
+    /// Returns the Optimization level of output.
+    ///
+    /// # Option
+    /// Returns [`None`] if no optimization level was specified.
+    pub fn opt_level(&self) -> Option<&str> {
+        self.opt_level.as_deref()
+    }
+
+    /// Returns the lossiness level.
+    ///
+    /// # Option
+    /// Returns `None` if no lossiness was given.
+    pub fn lossy(&self) -> Option<u32> {
+        self.lossy
+    }
+
+    /// Returns the number of colors the output GIF is reduced to.
+    ///
+    /// # Option
+    /// Returns [`None`] if the colors should not be reduced.
+    pub fn colors(&self) -> Option<u16> {
+        self.colors
+    }
+
+    /// Returns the dithering used when the colors of the output GIF are reduced.
+    ///
+    /// # Option
+    /// Returns [`None`] if Gifsicle should not dither.
+    pub fn dither(&self) -> Option<Dither> {
+        self.dither
+    }
+
+    /// Returns the maximum size of the output file in bytes.
+    ///
+    /// # Option
+    /// Returns [`None`] if the output size is not limited.
+    pub fn max_size(&self) -> Option<u64> {
+        self.max_size
+    }
+
+    /// Returns true if the GIF from before optimization should be kept.
+    pub fn keep_unoptimized(&self) -> bool {
+        self.keep_unoptimized
+    }
+
+    /// Returns true if GIFs that fail to decode should be repaired.
+    pub fn repair_gif(&self) -> bool {
+        self.repair_gif
+    }
+
+    /// Returns the comment to embed in the output GIF.
+    ///
+    /// # Option
+    /// Returns [`None`] if no comment should be embedded.
+    pub fn comment(&self) -> Option<&str> {
+        self.comment.as_deref()
+    }
+
+    /// Returns the delay of every output GIF frame in milliseconds.
+    ///
+    /// # Option
+    /// Returns [`None`] if the frames keep their own delays.
+    pub fn frame_delay(&self) -> Option<u32> {
+        self.frame_delay
+    }
+
+    /// Returns the playback speed of the output GIF.
+    ///
+    /// # Option
+    /// Returns [`None`] if the GIF plays at its own speed.
+    pub fn speed(&self) -> Option<f32> {
+        self.speed
+    }
+
+    /// Returns the speed of the GIF encoder, from 1 (best) to 30 (fastest).
+    pub fn gif_quality(&self) -> i32 {
+        self.gif_quality
+    }
+
+    /// Returns the file format captioned GIFs are written in.
+    pub fn output_format(&self) -> OutputFormat {
+        self.output_format
+    }
+
+    /// Returns the number of times the output GIF repeats.
+    ///
+    /// 0 means the GIF repeats forever.
+    pub fn loops(&self) -> u16 {
+        self.loops
+    }
+
+    /// Returns how many times the media plays in a looping video output.
+    ///
+    /// # Option
+    /// Returns [`None`] if no looping video was requested.
+    pub fn loop_video(&self) -> Option<u32> {
+        self.loop_video
+    }
+
+    /// Returns true if a video should be output as a GIF.
+    pub fn to_gif(&self) -> bool {
+        self.to_gif
+    }
+
+    /// Returns the even width the captioned output is scaled to.
+    ///
+    /// # Option
+    /// Returns [`None`] if the output keeps the width of the media.
+    pub fn scale_output(&self) -> Option<u32> {
+        self.scale_output
+    }
+
+    /// Returns the number of threads `FFmpeg` should encode with.
+    ///
+    /// # Option
+    /// Returns [`None`] if `FFmpeg` should choose.
+    pub fn encoder_threads(&self) -> Option<u32> {
+        self.encoder_threads
+    }
+
+    /// Returns the requested color range of the output video.
+    ///
+    /// # Option
+    /// Returns [`None`] if the range of the input should be kept.
+    pub fn color_range(&self) -> Option<ColorRange> {
+        self.color_range
+    }
+
+    /// Returns the constant rate factor of the output video.
+    ///
+    /// # Option
+    /// Returns [`None`] if `FFmpeg` should choose.
+    pub fn crf(&self) -> Option<u32> {
+        self.crf
+    }
+
+    /// Returns the encoder preset of the output video.
+    ///
+    /// # Option
+    /// Returns [`None`] if `FFmpeg` should choose.
+    pub fn preset(&self) -> Option<Preset> {
+        self.preset
+    }
+
+    /// Returns true if the audio should be removed from the output video.
+    pub fn no_audio(&self) -> bool {
+        self.no_audio
+    }
+
+    /// Returns the codec the audio of the output video is re-encoded with.
+    ///
+    /// # Option
+    /// Returns [`None`] if the audio should be copied as is.
+    pub fn audio_codec(&self) -> Option<&str> {
+        self.audio_codec.as_deref()
+    }
+
+    /// Returns the hardware encoder of the output video.
+    ///
+    /// # Option
+    /// Returns [`None`] if the video should be encoded in software.
+    pub fn hwaccel(&self) -> Option<HwAccel> {
+        self.hwaccel
+    }
+
+    /// Returns the directory intermediate files are dumped to.
+    ///
+    /// # Option
+    /// Returns [`None`] if no intermediate files should be written.
+    pub fn debug_dump(&self) -> Option<&Path> {
+        self.debug_dump.as_deref()
+    }
+
+    /// Returns the event log of the job.
+    pub fn events(&self) -> &EventLog {
+        &self.events
+    }
+
+    /// Returns the settings of the external programs the job runs.
+    pub fn tools(&self) -> &Tools {
+        &self.tools
+    }
+}
@@ -2,33 +2,46 @@
 //!
 //! The `crate::utils` module contains common functions, and enums.
 
-
-#[cfg(windows)]
-use indicatif::{ProgressBar, ProgressStyle};
-#[cfg(windows)]
-use log::{info, warn};
 #[cfg(windows)]
-use std::{env, fs::File, io::Read, io::Write};
+use std::env;
 
-use rand::{distributions::Alphanumeric, thread_rng, Rng};
 use anyhow::Context;
+use indicatif::{ProgressBar, ProgressStyle};
+use log::{debug, info, warn};
+use rand::{distributions::Alphanumeric, thread_rng, Rng};
 use std::{
+    ffi::OsStr,
+    fs::{self, File},
+    io::{Read, Write},
     iter,
     path::{Path, PathBuf},
+    thread,
+    time::Duration,
 };
 
 type Result<T> = std::result::Result<T, anyhow::Error>;
 
+/// How many times a download is tried before giving up.
+const DOWNLOAD_ATTEMPTS: u32 = 3;
+
 use crate::error::ErrorKind;
 
 /// Argument handling with [`Clap`].
 ///
 /// [`Clap`]: clap
 pub mod args;
+/// Structured event logging.
+pub mod events;
 /// Gif captioning.
 pub mod gif;
 /// Caption creation.
 pub mod image;
+/// Options of a single captioning job.
+pub mod job;
+/// External process execution.
+pub mod process;
+/// Updating the program to the newest release.
+pub mod update;
 /// Video captioning.
 pub mod video;
 
@@ -47,6 +60,41 @@ pub enum MediaType {
     Gif,
     /// `.mov` files.
     Mov,
+    /// `.ts` (MPEG transport stream) files.
+    Ts,
+    /// `.flv` (Flash video) files.
+    Flv,
+}
+
+impl MediaType {
+    /// Every supported media type.
+    ///
+    /// [`validate_format()`] only accepts the types listed here,
+    /// so new media types have to be added to it.
+    pub const ALL: [MediaType; 8] = [
+        MediaType::Mp4,
+        MediaType::Avi,
+        MediaType::Mkv,
+        MediaType::Webm,
+        MediaType::Gif,
+        MediaType::Mov,
+        MediaType::Ts,
+        MediaType::Flv,
+    ];
+
+    /// Returns the file extension of the media type, including the leading dot.
+    pub const fn extension(self) -> &'static str {
+        match self {
+            MediaType::Mp4 => ".mp4",
+            MediaType::Avi => ".avi",
+            MediaType::Mkv => ".mkv",
+            MediaType::Webm => ".webm",
+            MediaType::Gif => ".gif",
+            MediaType::Mov => ".mov",
+            MediaType::Ts => ".ts",
+            MediaType::Flv => ".flv",
+        }
+    }
 }
 
 #[derive(Debug, Clone, Copy)]
@@ -56,6 +104,16 @@ pub enum DepTy {
     Ffmpeg,
 }
 
+impl DepTy {
+    /// Returns the environment variable that overrides the path of the dependency.
+    pub const fn env_var(self) -> &'static str {
+        match self {
+            DepTy::Gifsicle => "UNLUSTIG_GIFSICLE",
+            DepTy::Ffmpeg => "UNLUSTIG_FFMPEG",
+        }
+    }
+}
+
 impl std::fmt::Display for DepTy {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         let x = match *self {
@@ -68,6 +126,8 @@ impl std::fmt::Display for DepTy {
 
 /// Writes [`Gifsicle`] and [`FFmpeg`] to the appdata folder on Windows.
 ///
+/// See [`Tools::exe()`] to use an executable given by the user instead.
+///
 /// # Errors
 /// Returns an error if the `%appdata%` variable does not exist.
 ///
@@ -103,15 +163,361 @@ pub fn appdata_init(dep: DepTy) -> anyhow::Result<PathBuf> {
     }
 }
 
-/// Generates a random name with 5 alphanumeric chars.
-pub fn random_name() -> String {
+/// Checks if `path` is a file that can be run.
+fn is_executable(path: &Path) -> bool {
+    let meta = match fs::metadata(path) {
+        Ok(meta) => meta,
+        Err(_) => return false,
+    };
+    #[cfg(unix)]
+    let runnable = {
+        use std::os::unix::fs::PermissionsExt;
+        meta.permissions().mode() & 0o111 != 0
+    };
+    #[cfg(windows)]
+    let runnable = true;
+    meta.is_file() && runnable
+}
+
+/// Describes the output of a finished job.
+#[derive(Debug, Clone)]
+pub struct Outcome {
+    /// Path the output was written to.
+    pub output: PathBuf,
+    /// Type of the input media.
+    pub media_type: MediaType,
+    /// Width and height of the output.
+    pub dimensions: (u32, u32),
+    /// Number of lines the caption text was wrapped into, 0 without caption text.
+    pub caption_lines: usize,
+    /// Whether Gifsicle optimized the output.
+    pub optimized: bool,
+}
+
+/// Settings of the external programs a job runs and of its temporary files.
+///
+/// They are handed to [`FFmpeg`] and [`Gifsicle`] as values,
+/// so jobs with different settings can run in one process.
+///
+/// [`FFmpeg`]: crate::utils::video::FFmpeg
+/// [`Gifsicle`]: crate::utils::gif::Gifsicle
+#[derive(Debug, Clone, Default)]
+pub struct Tools {
+    /// `FFmpeg` executable, [`None`] if it should be looked up as usual.
+    pub ffmpeg: Option<PathBuf>,
+    /// Gifsicle executable, [`None`] if it should be looked up as usual.
+    pub gifsicle: Option<PathBuf>,
+    /// Directory temporary files are written to, [`None`] for the system's temporary directory.
+    pub temp_dir: Option<PathBuf>,
+    /// Time FFmpeg and Gifsicle may run for, [`None`] if they may run for as long as they need.
+    pub timeout: Option<Duration>,
+}
+
+impl Tools {
+    /// Returns the settings given through the environment.
+    ///
+    /// `UNLUSTIG_FFMPEG` and `UNLUSTIG_GIFSICLE` set the executables,
+    /// see [`DepTy::env_var()`], and `UNLUSTIG_TIMEOUT` sets the timeout in seconds.
+    pub fn from_env() -> Self {
+        Self {
+            ffmpeg: std::env::var_os(DepTy::Ffmpeg.env_var()).map(PathBuf::from),
+            gifsicle: std::env::var_os(DepTy::Gifsicle.env_var()).map(PathBuf::from),
+            temp_dir: None,
+            timeout: process::default_timeout(),
+        }
+    }
+
+    /// Returns the executable of `dep`.
+    ///
+    /// The executable that was given is used if there is one, otherwise see [`appdata_init()`].
+    ///
+    /// # Errors
+    /// Returns [`NotAnExecutable`] if the given path is not an executable file,
+    /// or an error if [`appdata_init()`] fails.
+    ///
+    /// [`NotAnExecutable`]: crate::error::ErrorKind::NotAnExecutable
+    pub fn exe(&self, dep: DepTy) -> Result<PathBuf> {
+        let path = match dep {
+            DepTy::Ffmpeg => &self.ffmpeg,
+            DepTy::Gifsicle => &self.gifsicle,
+        };
+        let path = match path {
+            Some(path) => path.clone(),
+            None => return appdata_init(dep),
+        };
+        if !is_executable(&path) {
+            return Err(ErrorKind::NotAnExecutable {
+                dep: dep.to_string(),
+                path,
+            }
+            .into());
+        }
+        debug!("using {dep} at {}", path.display());
+        Ok(path)
+    }
+
+    /// Returns the directory temporary files are written to.
+    pub fn temp_dir(&self) -> PathBuf {
+        self.temp_dir.clone().unwrap_or_else(std::env::temp_dir)
+    }
+
+    /// Returns a randomly named path in the temporary directory.
+    ///
+    /// `ext` is appended as is, so it should include the leading dot.
+    /// The name starts with [`run_temp_prefix()`], so `clean` leaves it alone.
+    pub fn temp_path(&self, ext: &str) -> PathBuf {
+        self.temp_dir().join(format!(
+            "{}{}{ext}",
+            run_temp_prefix(),
+            random_name(TEMP_NAME_LEN)
+        ))
+    }
+
+    /// Reserves a randomly named [`TempFile`] in the temporary directory.
+    ///
+    /// `ext` is appended as is, so it should include the leading dot.
+    /// The file itself is not created.
+    pub fn temp_file(&self, ext: &str) -> TempFile {
+        TempFile {
+            path: self.temp_path(ext),
+        }
+    }
+
+    /// Checks that files can be written to the temporary directory.
+    ///
+    /// # Errors
+    /// Returns [`TempDirUnwritable`] if a probe file cannot be written.
+    ///
+    /// [`TempDirUnwritable`]: crate::error::ErrorKind::TempDirUnwritable
+    pub fn check_temp_dir(&self) -> Result<()> {
+        let probe = self.temp_path(".probe");
+        if let Err(err) = fs::write(&probe, b"unlustig") {
+            debug!("failed to write {}: {err}", probe.display());
+            return Err(ErrorKind::TempDirUnwritable(self.temp_dir()).into());
+        }
+        if let Err(err) = fs::remove_file(&probe) {
+            debug!("failed to remove {}: {err}", probe.display());
+        }
+        Ok(())
+    }
+}
+
+/// A file in the temporary directory that is removed when dropped.
+#[derive(Debug)]
+pub struct TempFile {
+    path: PathBuf,
+}
+
+impl TempFile {
+    /// Returns the path of the temporary file.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for TempFile {
+    /// Removes the temporary file if it was created.
+    fn drop(&mut self) {
+        if let Err(err) = std::fs::remove_file(&self.path) {
+            debug!("failed to remove {}: {err}", self.path.display());
+        }
+    }
+}
+
+/// Prefix of every file the program creates in the temporary directory.
+const TEMP_PREFIX: &str = "unlustig-";
+
+/// How long a temporary file is left alone by `clean`.
+///
+/// Younger files may still be used by another run of the program,
+/// like media buffered from stdin or a caption waiting for `FFmpeg`.
+const STALE_TEMP_AGE: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Returns the prefix of the temporary files of this run of the program.
+///
+/// It holds the process id, so the files of this run can be told from those of others.
+fn run_temp_prefix() -> String {
+    format!("{TEMP_PREFIX}{}-", std::process::id())
+}
+
+/// Length of the random part of temporary file names.
+///
+/// Longer than output names, as the temporary directory may be shared by many runs.
+const TEMP_NAME_LEN: usize = 16;
+
+/// Length of random output names.
+const OUTPUT_NAME_LEN: usize = 5;
+
+/// Checks that the output can be written to `dir`.
+///
+/// A missing directory is created if `create` is set.
+///
+/// # Errors
+/// Returns [`OutputDirMissing`] if the directory does not exist,
+/// or [`OutputDirUnwritable`] if a probe file cannot be written to it.
+///
+/// [`OutputDirMissing`]: crate::error::ErrorKind::OutputDirMissing
+/// [`OutputDirUnwritable`]: crate::error::ErrorKind::OutputDirUnwritable
+pub fn check_output_dir(dir: &Path, create: bool) -> Result<()> {
+    if !dir.is_dir() {
+        if !create {
+            return Err(ErrorKind::OutputDirMissing(dir.to_path_buf()).into());
+        }
+        fs::create_dir_all(dir).context(format!(
+            "failed to create output directory: {}",
+            dir.display()
+        ))?;
+        info!("Created output directory {}", dir.display());
+    }
+    let probe = dir.join(format!("{TEMP_PREFIX}{}.probe", random_name(TEMP_NAME_LEN)));
+    if let Err(err) = fs::write(&probe, b"unlustig") {
+        debug!("failed to write {}: {err}", probe.display());
+        return Err(ErrorKind::OutputDirUnwritable(dir.to_path_buf()).into());
+    }
+    if let Err(err) = fs::remove_file(&probe) {
+        debug!("failed to remove {}: {err}", probe.display());
+    }
+    Ok(())
+}
+
+/// Writes an intermediate image named `name` to the `--debug-dump` directory.
+///
+/// Does nothing if `dir` is `None`.
+///
+/// # Errors
+/// Returns an error if the directory cannot be created or the image cannot be saved.
+pub fn dump_image(
+    dir: Option<&Path>,
+    name: &str,
+    image: &::image::ImageBuffer<::image::Rgba<u8>, Vec<u8>>,
+) -> Result<()> {
+    if let Some(dir) = dir {
+        fs::create_dir_all(dir).context(format!("failed to create {}", dir.display()))?;
+        image
+            .save(dir.join(name))
+            .context(format!("failed to dump {name}"))?;
+        debug!("dumped {}", dir.join(name).display());
+    }
+    Ok(())
+}
+
+/// Number of lines of a child process's stderr kept in error messages.
+const STDERR_TAIL_LINES: usize = 20;
+
+/// Returns the last lines a child process printed to `stderr`.
+///
+/// The end of the output usually holds the actual error, the rest is progress and banners.
+pub fn stderr_tail(stderr: &[u8]) -> String {
+    let stderr = String::from_utf8_lossy(stderr);
+    let lines: Vec<_> = stderr.trim().lines().collect();
+    lines[lines.len().saturating_sub(STDERR_TAIL_LINES)..].join("\n")
+}
+
+/// Writes an intermediate text file named `name` to the `--debug-dump` directory.
+///
+/// Does nothing if `dir` is `None`.
+///
+/// # Errors
+/// Returns an error if the directory or the file cannot be written.
+pub fn dump_text(dir: Option<&Path>, name: &str, text: &str) -> Result<()> {
+    if let Some(dir) = dir {
+        fs::create_dir_all(dir).context(format!("failed to create {}", dir.display()))?;
+        fs::write(dir.join(name), text).context(format!("failed to dump {name}"))?;
+        debug!("dumped {}", dir.join(name).display());
+    }
+    Ok(())
+}
+
+/// Removes downloaded dependencies, cached captions and leftover temporary files.
+///
+/// Dependencies that were found on the `PATH` are never touched.
+/// Temporary files are only removed once they are older than [`STALE_TEMP_AGE`],
+/// and never if they belong to this run.
+///
+/// # Errors
+/// Returns an error if a file or directory cannot be removed.
+pub fn cleanup(tools: &Tools) -> Result<()> {
+    let mut stale = Vec::new();
+    #[cfg(windows)]
+    stale.push(PathBuf::from(env::var("APPDATA")?).join("unlustig-rs"));
+    if let Some(cache) = dirs::cache_dir() {
+        stale.push(cache.join("unlustig-rs"));
+    }
+
+    for dir in stale.iter().filter(|dir| dir.exists()) {
+        fs::remove_dir_all(dir).context(format!("failed to remove {}", dir.display()))?;
+        info!("Removed {}", dir.display());
+    }
+
+    let removed = remove_stale_temp_files(&tools.temp_dir(), STALE_TEMP_AGE)?;
+    info!("Removed {removed} temporary file(s)");
+    Ok(())
+}
+
+/// Removes the temporary files of other runs in `dir` that were last written
+/// at least `max_age` ago. Returns the number of removed files.
+///
+/// # Errors
+/// Returns an error if `dir` cannot be read or a file cannot be removed.
+fn remove_stale_temp_files(dir: &Path, max_age: Duration) -> Result<usize> {
+    let this_run = run_temp_prefix();
+    let mut removed = 0;
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let path = entry.path();
+        let ours = path
+            .file_name()
+            .and_then(OsStr::to_str)
+            .map_or(false, |name| {
+                name.starts_with(TEMP_PREFIX) && !name.starts_with(&this_run)
+            });
+        // a file without a readable age could be in use, it is kept.
+        let stale = entry
+            .metadata()
+            .and_then(|meta| meta.modified())
+            .ok()
+            .and_then(|modified| modified.elapsed().ok())
+            .map_or(false, |age| age >= max_age);
+        if ours && stale && path.is_file() {
+            fs::remove_file(&path).context(format!("failed to remove {}", path.display()))?;
+            removed += 1;
+        }
+    }
+    Ok(removed)
+}
+
+/// Returns the path the output named `name` is written to in `out_path`.
+///
+/// If a file with that name exists and overwriting is disabled,
+/// a name from [`random_output_name()`] is prepended to it, e.g. `aB3x9-out.gif`.
+pub fn resolve_output_path(out_path: &Path, name: &str, overwrite: bool) -> PathBuf {
+    let path = out_path.join(name);
+    if !path.exists() {
+        return path;
+    }
+    if overwrite {
+        info!("Overwrite is enabled. Any file with the same name ({name}) will be overwritten by the output file.");
+        path
+    } else {
+        warn!("Overwrite is disabled. File with a similar name found. Modifying name.");
+        out_path.join(format!("{}-{name}", random_output_name()))
+    }
+}
+
+/// Generates a random name of `len` alphanumeric chars.
+pub fn random_name(len: usize) -> String {
     let mut rng = thread_rng();
     iter::repeat(())
         .map(|_| char::from(rng.sample(Alphanumeric)))
-        .take(5)
+        .take(len)
         .collect()
 }
 
+/// Generates a random name for output files, 5 alphanumeric chars long.
+pub fn random_output_name() -> String {
+    random_name(OUTPUT_NAME_LEN)
+}
+
 impl DepTy {
     #[cfg(windows)]
     /// Downloads the specified dependency.
@@ -126,71 +532,256 @@ impl DepTy {
             DepTy::Ffmpeg => "https://github.com/bltzxsd/unlustig/raw/main/deps/ffmpeg/ffmpeg.exe",
         };
 
-        let request = ureq::get(url).call()?;
+        let fname = url.split('/').last().unwrap_or("unknown");
+        let unlustig = PathBuf::from(env::var("APPDATA")?).join("unlustig-rs");
+        std::fs::create_dir_all(&unlustig)?;
+        download(url, &unlustig.join(fname))
+    }
+}
 
-        let size: u64 = request
-            .header("content-length")
-            .context("failed to get download size")?
-            .parse()?;
+/// Downloads `url` to `path`, retrying failed downloads.
+///
+/// The file only appears at `path` once it was downloaded completely.
+///
+/// # Errors
+/// Returns an error if every attempt fails.
+pub(crate) fn download(url: &str, path: &Path) -> Result<()> {
+    let fname = path.file_name().map_or_else(
+        || url.to_string(),
+        |name| name.to_string_lossy().into_owned(),
+    );
+    let mut partial = path.as_os_str().to_owned();
+    partial.push(".part");
+    let partial = PathBuf::from(partial);
 
-        let fname = url.split('/').last().unwrap_or("unknown");
-        let bytes = human_bytes::human_bytes(size as f64);
+    let mut attempt = 1;
+    while let Err(err) = fetch(url, &fname, &partial) {
+        if attempt == DOWNLOAD_ATTEMPTS {
+            if let Err(e) = fs::remove_file(&partial) {
+                debug!("failed to remove {}: {e}", partial.display());
+            }
+            return Err(err);
+        }
+        let wait = Duration::from_secs(1 << attempt);
+        warn!(
+            "Downloading {fname} failed (attempt {attempt}/{DOWNLOAD_ATTEMPTS}): {err:#}\nRetrying in {}s...",
+            wait.as_secs()
+        );
+        thread::sleep(wait);
+        attempt += 1;
+    }
+    fs::rename(&partial, path)?;
 
-        info!("Downloading {fname} - {bytes}");
+    Ok(())
+}
 
-        let chunk_size = 1024usize;
+/// Downloads `url` to `path`, replacing anything that was there.
+///
+/// # Errors
+/// Returns an error if the request fails or the download is cut short.
+fn fetch(url: &str, fname: &str, path: &Path) -> Result<()> {
+    let request = ureq::get(url).call()?;
 
-        let pb = ProgressBar::new(size);
-        pb.set_style(ProgressStyle::default_bar()
-            .template("{spinner:.green} [{elapsed_precise}] [{wide_bar:.cyan/blue}] {bytes}/{total_bytes} ({eta})")
-            .progress_chars("#>-"));
+    let size: u64 = request
+        .header("content-length")
+        .context("failed to get download size")?
+        .parse()?;
 
-        let mut buf = Vec::new();
-        let mut reader = request.into_reader();
+    let bytes = human_bytes::human_bytes(size as f64);
 
-        loop {
-            let mut buffer = vec![0; chunk_size];
-            let bcount = reader.read(&mut buffer[..])?;
-            buffer.truncate(bcount);
-            if !buffer.is_empty() {
-                buf.extend(buffer.into_boxed_slice().into_vec().iter().copied());
-                pb.inc(bcount as _);
-            } else {
-                break;
-            }
-        }
+    info!("Downloading {fname} - {bytes}");
 
-        pb.finish();
-        let unlustig = PathBuf::from(env::var("APPDATA")?).join("unlustig-rs");
-        std::fs::create_dir_all(&unlustig)?;
-        let mut file = File::create(&unlustig.join(fname))?;
-        file.write_all(&buf)?;
+    let chunk_size = 1024usize;
 
-        Ok(())
+    let pb = ProgressBar::new(size);
+    pb.set_style(ProgressStyle::default_bar()
+        .template("{spinner:.green} [{elapsed_precise}] [{wide_bar:.cyan/blue}] {bytes}/{total_bytes} ({eta})")
+        .progress_chars("#>-"));
+
+    let mut file = File::create(path)?;
+    let mut reader = request.into_reader();
+    let mut written = 0;
+    let mut buffer = vec![0; chunk_size];
+
+    loop {
+        let bcount = reader.read(&mut buffer[..])?;
+        if bcount == 0 {
+            break;
+        }
+        file.write_all(&buffer[..bcount])?;
+        written += bcount as u64;
+        pb.inc(bcount as _);
     }
+
+    pb.finish();
+    if written != size {
+        anyhow::bail!("download was cut short after {written} of {size} bytes");
+    }
+    Ok(())
 }
 
 /// Validate file formats.
 ///
+/// Extensions are mapped to the types of [`MediaType::ALL`],
+/// matched case-insensitively (`.MOV` from phones).
+///
 /// # Errors
 /// Returns [`UnsupportedMediaFormat`] if file is unsupported.
 ///
 /// [`UnsupportedMediaFormat`]: crate::error::ErrorKind::UnsupportedMediaFormat
 pub fn validate_format(path: &Path) -> Result<MediaType> {
-    match path
+    let ext = path
         .extension()
         .context(format!("failed to get file extension: {}", path.display()))?
-        .to_str()
-        .context(format!(
-            "failed to convert Path->OsStr to str: {}",
-            path.display()
-        ))? {
-        "mp4" => Ok(MediaType::Mp4),
-        "avi" => Ok(MediaType::Avi),
-        "mkv" => Ok(MediaType::Mkv),
-        "webm" => Ok(MediaType::Webm),
-        "gif" => Ok(MediaType::Gif),
-        "mov" => Ok(MediaType::Mov),
+        .to_string_lossy()
+        .to_ascii_lowercase();
+    if let Some(ty) = MediaType::ALL
+        .into_iter()
+        .find(|ty| ty.extension().trim_start_matches('.') == ext)
+    {
+        return Ok(ty);
+    }
+    match ext.as_str() {
+        // HEIF decoding needs still image captioning, which does not exist yet.
+        ext @ ("heic" | "heif") => Err(ErrorKind::UnsupportedMediaFormat(format!(
+            "{ext} (still images are not supported yet, convert the photo to a GIF or video first)"
+        ))
+        .into()),
         ext => Err(ErrorKind::UnsupportedMediaFormat(ext.to_string()).into()),
     }
 }
+
+/// Detects the media type from the leading bytes of a file.
+///
+/// Used for input that has no file extension, like media piped through stdin.
+pub fn sniff_format(bytes: &[u8]) -> Option<MediaType> {
+    match bytes {
+        [b'G', b'I', b'F', b'8', b'7' | b'9', b'a', ..] => Some(MediaType::Gif),
+        [_, _, _, _, b'f', b't', b'y', b'p', b'q', b't', b' ', b' ', ..]
+        | [_, _, _, _, b'm', b'o', b'o', b'v', ..]
+        | [_, _, _, _, b'w', b'i', b'd', b'e', ..] => Some(MediaType::Mov),
+        [_, _, _, _, b'f', b't', b'y', b'p', ..] => Some(MediaType::Mp4),
+        [b'R', b'I', b'F', b'F', _, _, _, _, b'A', b'V', b'I', b' ', ..] => Some(MediaType::Avi),
+        [b'F', b'L', b'V', 0x01, ..] => Some(MediaType::Flv),
+        // transport streams are 188 byte packets starting with a sync byte.
+        [0x47, ..] if bytes.get(188) == Some(&0x47) && bytes.get(376) == Some(&0x47) => {
+            Some(MediaType::Ts)
+        }
+        // Matroska and WebM share the EBML header, the doctype tells them apart.
+        [0x1A, 0x45, 0xDF, 0xA3, ..] => {
+            let header = &bytes[..bytes.len().min(64)];
+            if header.windows(4).any(|doctype| doctype == b"webm") {
+                Some(MediaType::Webm)
+            } else {
+                Some(MediaType::Mkv)
+            }
+        }
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Creates an empty directory that is removed when dropped.
+    struct TestDir(PathBuf);
+
+    impl TestDir {
+        fn new() -> Self {
+            let dir = std::env::temp_dir().join(format!("{TEMP_PREFIX}test-{}", random_name(8)));
+            fs::create_dir_all(&dir).unwrap();
+            Self(dir)
+        }
+    }
+
+    impl Drop for TestDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    #[test]
+    fn output_path_without_collision_keeps_the_name() {
+        let dir = TestDir::new();
+        for overwrite in [false, true] {
+            assert_eq!(
+                resolve_output_path(&dir.0, "out.gif", overwrite),
+                dir.0.join("out.gif")
+            );
+        }
+    }
+
+    #[test]
+    fn output_path_with_overwrite_replaces_the_file() {
+        let dir = TestDir::new();
+        fs::write(dir.0.join("out.gif"), b"old").unwrap();
+        assert_eq!(
+            resolve_output_path(&dir.0, "out.gif", true),
+            dir.0.join("out.gif")
+        );
+    }
+
+    #[test]
+    fn output_path_without_overwrite_prefixes_a_random_name() {
+        let dir = TestDir::new();
+        fs::write(dir.0.join("out.gif"), b"old").unwrap();
+        let path = resolve_output_path(&dir.0, "out.gif", false);
+        assert_eq!(path.parent(), Some(dir.0.as_path()));
+        let name = path.file_name().and_then(OsStr::to_str).unwrap();
+        let (prefix, rest) = name.split_at(OUTPUT_NAME_LEN);
+        assert_eq!(rest, "-out.gif");
+        assert!(prefix.chars().all(|c| c.is_ascii_alphanumeric()));
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn ts_and_flv_files_are_recognized_by_extension() {
+        for (name, ty) in [
+            ("clip.ts", MediaType::Ts),
+            ("CLIP.TS", MediaType::Ts),
+            ("stream.flv", MediaType::Flv),
+            ("STREAM.FLV", MediaType::Flv),
+        ] {
+            assert_eq!(validate_format(Path::new(name)).unwrap(), ty, "{name}");
+        }
+    }
+
+    #[test]
+    fn ts_and_flv_are_sniffed_from_their_headers() {
+        let mut ts = vec![0; 3 * 188];
+        for packet in ts.chunks_mut(188) {
+            packet[0] = 0x47;
+        }
+        assert_eq!(sniff_format(&ts), Some(MediaType::Ts));
+        // a single sync byte is not enough to tell a transport stream.
+        assert_eq!(sniff_format(&ts[..188]), None);
+
+        let flv = [b'F', b'L', b'V', 0x01, 0x05, 0, 0, 0, 0x09];
+        assert_eq!(sniff_format(&flv), Some(MediaType::Flv));
+    }
+
+    #[test]
+    fn clean_keeps_young_and_own_temporary_files() {
+        let dir = TestDir::new();
+        let other_run = std::process::id().wrapping_add(1);
+        let other = dir.0.join(format!("{TEMP_PREFIX}{other_run}-other.png"));
+        let tools = Tools {
+            temp_dir: Some(dir.0.clone()),
+            ..Tools::default()
+        };
+        let own = tools.temp_path(".png");
+        let unrelated = dir.0.join("notes.txt");
+        for path in [&other, &own, &unrelated] {
+            fs::write(path, b"in use").unwrap();
+        }
+
+        assert_eq!(remove_stale_temp_files(&dir.0, STALE_TEMP_AGE).unwrap(), 0);
+        assert!(other.exists());
+
+        // with no age limit, only the files of other runs go.
+        assert_eq!(remove_stale_temp_files(&dir.0, Duration::ZERO).unwrap(), 1);
+        assert!(!other.exists());
+        assert!(own.exists() && unrelated.exists());
+    }
+}
@@ -0,0 +1,94 @@
+use std::{
+    io::Read,
+    process::{Command, Output, Stdio},
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+use anyhow::{Context, Result};
+
+use crate::error::ErrorKind;
+
+/// Environment variable holding the timeout of external processes in seconds.
+const TIMEOUT_VAR: &str = "UNLUSTIG_TIMEOUT";
+
+/// How often a running process is checked for having exited.
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Returns how long external processes may run, as given through `UNLUSTIG_TIMEOUT`.
+///
+/// # Option
+/// Returns [`None`] if processes may run for as long as they need.
+pub fn default_timeout() -> Option<Duration> {
+    std::env::var(TIMEOUT_VAR)
+        .ok()
+        .and_then(|secs| secs.parse().ok())
+        .filter(|secs| *secs > 0)
+        .map(Duration::from_secs)
+}
+
+/// Runs `command` to completion and collects its output, like [`Command::output()`].
+///
+/// The process is killed once it runs for longer than `timeout`, if one is given.
+///
+/// # Errors
+/// Returns an error if the process cannot be started,
+/// or [`ProcessTimedOut`] if it was killed.
+///
+/// [`ProcessTimedOut`]: crate::error::ErrorKind::ProcessTimedOut
+pub fn output(command: &mut Command, timeout: Option<Duration>) -> Result<Output> {
+    let program = command.get_program().to_string_lossy().into_owned();
+    let timeout = match timeout {
+        Some(timeout) => timeout,
+        None => {
+            return command
+                .output()
+                .context(format!("failed to start {program}"))
+        }
+    };
+
+    let mut child = command
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .context(format!("failed to start {program}"))?;
+    // the pipes are drained while waiting, so a chatty process never blocks on a full pipe.
+    let stdout = drain(child.stdout.take());
+    let stderr = drain(child.stderr.take());
+
+    let deadline = Instant::now() + timeout;
+    let status = loop {
+        if let Some(status) = child.try_wait()? {
+            break status;
+        }
+        if Instant::now() >= deadline {
+            // the process may have exited in the meantime, which is fine.
+            let _ = child.kill();
+            child.wait()?;
+            return Err(ErrorKind::ProcessTimedOut {
+                program,
+                seconds: timeout.as_secs(),
+            }
+            .into());
+        }
+        thread::sleep(POLL_INTERVAL);
+    };
+    Ok(Output {
+        status,
+        stdout: stdout.join().unwrap_or_default(),
+        stderr: stderr.join().unwrap_or_default(),
+    })
+}
+
+/// Reads `pipe` to its end on another thread.
+fn drain<R: Read + Send + 'static>(pipe: Option<R>) -> JoinHandle<Vec<u8>> {
+    thread::spawn(move || {
+        let mut buf = Vec::new();
+        if let Some(mut pipe) = pipe {
+            // a broken pipe only cuts the output short.
+            let _ = pipe.read_to_end(&mut buf);
+        }
+        buf
+    })
+}
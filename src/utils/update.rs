@@ -0,0 +1,326 @@
+use std::{
+    env, fs,
+    io::{self, Write},
+    path::Path,
+    time::Duration,
+};
+
+use anyhow::{Context, Result};
+use log::{debug, info};
+use semver::Version;
+use serde_json::Value;
+use yansi::Paint;
+
+use crate::error::ErrorKind;
+
+use super::download;
+
+/// Lists the releases of the program on GitHub, newest first.
+const RELEASES_URL: &str = "https://api.github.com/repos/bltzxsd/unlustig/releases";
+
+/// Environment variable disabling the update check and `self-update`.
+pub const NO_UPDATE_VAR: &str = "UNLUSTIG_NO_UPDATE";
+
+/// How long looking up the newest release for `self-update` may take.
+const LOOKUP_TIMEOUT_SECS: u64 = 10;
+
+/// A stable release of the program.
+#[derive(Debug, Clone)]
+pub struct Release {
+    /// Version of the release.
+    pub version: Version,
+    /// Names and download URLs of the files of the release.
+    pub assets: Vec<(String, String)>,
+}
+
+impl Release {
+    /// Returns the name and download URL of the portable executable for this platform.
+    ///
+    /// # Option
+    /// Returns [`None`] if the release has no executable for this platform.
+    pub fn executable(&self) -> Option<(&str, &str)> {
+        self.assets
+            .iter()
+            .map(|(name, url)| (name.as_str(), url.as_str()))
+            .find(|(name, _)| is_executable(name, env::consts::OS, env::consts::ARCH))
+    }
+}
+
+/// Returns the names release files use for the operating system `os`.
+fn os_names(os: &str) -> &'static [&'static str] {
+    match os {
+        "linux" => &["linux"],
+        "macos" => &["macos", "darwin", "apple"],
+        "windows" => &["windows"],
+        _ => &[],
+    }
+}
+
+/// Returns the names release files use for the CPU architecture `arch`.
+fn arch_names(arch: &str) -> &'static [&'static str] {
+    match arch {
+        "x86_64" => &["x86_64", "amd64", "x64"],
+        "aarch64" => &["aarch64", "arm64"],
+        "x86" => &["i686", "i386"],
+        _ => &[],
+    }
+}
+
+/// Returns true if the release file `name` is the portable executable for `os` and `arch`,
+/// as named by [`std::env::consts`].
+///
+/// Both have to be part of the name, so one platform never picks the binary of another.
+/// Installers and packages like `.msi`, `.deb` and `PKGBUILD` are skipped.
+fn is_executable(name: &str, os: &str, arch: &str) -> bool {
+    let name = name.to_ascii_lowercase();
+    let names = |names: &[&str]| names.iter().any(|tag| name.contains(tag));
+    if !name.starts_with(env!("CARGO_PKG_NAME")) || !names(os_names(os)) || !names(arch_names(arch))
+    {
+        return false;
+    }
+    if os == "windows" {
+        name.ends_with(".exe")
+    } else {
+        !name.contains('.')
+    }
+}
+
+/// Returns the version of the running program.
+///
+/// # Errors
+/// Returns an error if the crate version is not valid semver.
+pub fn current_version() -> Result<Version> {
+    Ok(clap::crate_version!().parse()?)
+}
+
+/// Looks up the newest stable release on GitHub.
+///
+/// # Errors
+/// Returns an error if GitHub does not answer within `timeout`
+/// or lists no stable release.
+pub fn latest_release(timeout: Duration) -> Result<Release> {
+    let agent = ureq::AgentBuilder::new().timeout(timeout).build();
+    let data = agent.get(RELEASES_URL).call()?.into_string()?;
+    let releases = serde_json::from_str::<Value>(&data)?;
+    // the newest release comes first, but it may be a draft or a prerelease.
+    let release = releases
+        .as_array()
+        .into_iter()
+        .flatten()
+        .find(|release| {
+            !release["draft"].as_bool().unwrap_or(false)
+                && !release["prerelease"].as_bool().unwrap_or(false)
+                && release["tag_name"].is_string()
+        })
+        .context("no stable release found")?;
+    let tag = release["tag_name"].as_str().unwrap_or_default();
+    let tag = tag.strip_prefix('v').unwrap_or(tag);
+    let version = tag.parse().context(format!("invalid release tag: {tag}"))?;
+    let assets = release["assets"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .filter_map(|asset| {
+            let name = asset["name"].as_str()?;
+            let url = asset["browser_download_url"].as_str()?;
+            Some((name.to_string(), url.to_string()))
+        })
+        .collect();
+    Ok(Release { version, assets })
+}
+
+/// Replaces the running executable with the newest release.
+///
+/// The user is asked to confirm the update unless `yes` is set. The release is
+/// downloaded next to the executable, checked to be an executable for this
+/// platform and then moved over the running one.
+///
+/// # Errors
+/// Returns [`NoReleaseExecutable`] if the release has no executable for this platform,
+/// [`InvalidReleaseExecutable`] if the download is not one, or an error if
+/// the release cannot be looked up, downloaded or installed.
+///
+/// [`NoReleaseExecutable`]: crate::error::ErrorKind::NoReleaseExecutable
+/// [`InvalidReleaseExecutable`]: crate::error::ErrorKind::InvalidReleaseExecutable
+pub fn self_update(yes: bool) -> Result<()> {
+    let exe = env::current_exe().context("failed to find the running executable")?;
+    remove_replaced(&exe);
+    let current = current_version()?;
+    let release = latest_release(Duration::from_secs(LOOKUP_TIMEOUT_SECS))?;
+    if release.version <= current {
+        info!("unlustig {current} is up to date.");
+        return Ok(());
+    }
+    let (name, url) = release
+        .executable()
+        .ok_or_else(|| ErrorKind::NoReleaseExecutable(release.version.to_string()))?;
+    let question = format!(
+        "Replace unlustig {current} at {} with {}?",
+        exe.display(),
+        release.version
+    );
+    if !yes && !confirm(&question)? {
+        info!("Update cancelled.");
+        return Ok(());
+    }
+
+    // the download goes next to the executable, so it can be renamed over it.
+    let new = exe.with_file_name(format!("{name}.new"));
+    download(url, &new)?;
+    if let Err(err) = verify(&new).and_then(|()| replace(&exe, &new)) {
+        if let Err(e) = fs::remove_file(&new) {
+            debug!("failed to remove {}: {e}", new.display());
+        }
+        return Err(err);
+    }
+    info!(
+        "{} unlustig to {}.",
+        Paint::green("Updated"),
+        release.version
+    );
+    Ok(())
+}
+
+/// Asks the user a yes or no `question` on the terminal. Anything but yes is a no.
+///
+/// # Errors
+/// Returns an error if the terminal cannot be written to or read from.
+fn confirm(question: &str) -> Result<bool> {
+    print!("{question} [y/N] ");
+    io::stdout().flush()?;
+    let mut answer = String::new();
+    io::stdin().read_line(&mut answer)?;
+    Ok(matches!(
+        answer.trim().to_ascii_lowercase().as_str(),
+        "y" | "yes"
+    ))
+}
+
+/// Checks that the downloaded file at `path` is an executable for this platform.
+///
+/// # Errors
+/// Returns [`InvalidReleaseExecutable`] if the file does not start like one.
+///
+/// [`InvalidReleaseExecutable`]: crate::error::ErrorKind::InvalidReleaseExecutable
+fn verify(path: &Path) -> Result<()> {
+    let data = fs::read(path)?;
+    if !starts_like_executable(&data, env::consts::OS) {
+        return Err(ErrorKind::InvalidReleaseExecutable(path.to_path_buf()).into());
+    }
+    Ok(())
+}
+
+/// Returns true if `data` starts with the magic bytes of an executable for `os`.
+///
+/// Windows runs PE files, macOS runs Mach-O files, thin or universal, and
+/// the other Unix systems run ELF files.
+fn starts_like_executable(data: &[u8], os: &str) -> bool {
+    let magics: &[&[u8]] = match os {
+        "windows" => &[b"MZ"],
+        "macos" => &[
+            &[0xcf, 0xfa, 0xed, 0xfe],
+            &[0xce, 0xfa, 0xed, 0xfe],
+            &[0xca, 0xfe, 0xba, 0xbe],
+        ],
+        _ => &[b"\x7fELF"],
+    };
+    magics.iter().any(|magic| data.starts_with(magic))
+}
+
+/// Returns the path a replaced executable is moved to.
+fn replaced_path(exe: &Path) -> std::path::PathBuf {
+    let mut name = exe.as_os_str().to_owned();
+    name.push(".old");
+    name.into()
+}
+
+/// Removes the executable replaced by an earlier update, if any.
+fn remove_replaced(exe: &Path) {
+    let old = replaced_path(exe);
+    if old.exists() {
+        if let Err(e) = fs::remove_file(&old) {
+            debug!("failed to remove {}: {e}", old.display());
+        }
+    }
+}
+
+/// Moves the executable at `new` over the running executable `exe`.
+///
+/// A running executable cannot be overwritten on Windows, but it can be renamed,
+/// so it is moved aside first and removed by the next update.
+///
+/// # Errors
+/// Returns an error if the executable cannot be replaced.
+fn replace(exe: &Path, new: &Path) -> Result<()> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        fs::set_permissions(new, fs::Permissions::from_mode(0o755))?;
+    }
+    let context = || {
+        format!(
+            "failed to replace {}, unlustig may have to be updated with the package manager or installer",
+            exe.display()
+        )
+    };
+    if cfg!(windows) {
+        let old = replaced_path(exe);
+        fs::rename(exe, &old).with_context(context)?;
+        if let Err(err) = fs::rename(new, exe) {
+            // put the running executable back, so the program is not lost.
+            if let Err(e) = fs::rename(&old, exe) {
+                debug!("failed to restore {}: {e}", exe.display());
+            }
+            return Err(anyhow::Error::new(err).context(context()));
+        }
+        return Ok(());
+    }
+    fs::rename(new, exe).with_context(context)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ASSETS: [&str; 6] = [
+        "unlustig-linux-x86_64",
+        "unlustig-macos-aarch64",
+        "unlustig-macos-x86_64",
+        "unlustig-windows-x86_64.exe",
+        "unlustig-linux-x86_64.deb",
+        "PKGBUILD",
+    ];
+
+    fn pick(os: &str, arch: &str) -> Option<&'static str> {
+        ASSETS
+            .iter()
+            .copied()
+            .find(|name| is_executable(name, os, arch))
+    }
+
+    #[test]
+    fn executable_is_picked_by_os_and_arch() {
+        assert_eq!(pick("linux", "x86_64"), Some("unlustig-linux-x86_64"));
+        assert_eq!(pick("macos", "aarch64"), Some("unlustig-macos-aarch64"));
+        assert_eq!(pick("macos", "x86_64"), Some("unlustig-macos-x86_64"));
+        assert_eq!(
+            pick("windows", "x86_64"),
+            Some("unlustig-windows-x86_64.exe")
+        );
+        assert_eq!(pick("linux", "aarch64"), None);
+        assert_eq!(pick("freebsd", "x86_64"), None);
+    }
+
+    #[test]
+    fn executables_are_checked_for_the_format_of_the_os() {
+        let elf = b"\x7fELF\x02\x01\x01";
+        let mach_o = [0xcf, 0xfa, 0xed, 0xfe, 0x0c, 0x00, 0x00, 0x01];
+        let pe = b"MZ\x90\x00";
+        assert!(starts_like_executable(elf, "linux"));
+        assert!(starts_like_executable(&mach_o, "macos"));
+        assert!(starts_like_executable(pe, "windows"));
+        assert!(!starts_like_executable(elf, "macos"));
+        assert!(!starts_like_executable(&mach_o, "linux"));
+        assert!(!starts_like_executable(b"<!DOCTYPE html>", "windows"));
+    }
+}
@@ -1,51 +1,96 @@
 use std::{
-    env,
+    fs,
     path::{Path, PathBuf},
     process::Command,
 };
 
 use anyhow::{Context, Result};
-use image::GenericImageView;
+use human_bytes::human_bytes;
+use image::{ImageBuffer, Rgba};
 use log::{info, warn};
 use rusttype::Font;
 use yansi::Paint;
 
-use crate::utils::{
-    image::{SetUp, TextImage},
-    DepTy,
+use crate::{
+    error::ErrorKind,
+    utils::{
+        args::{CaptionPosition, ColorRange, HwAccel, OverlayMode},
+        gif::finish_gif,
+        image::{
+            caption_lines, check_media_dimensions, check_output_height, fade, feather_fringe,
+            layers_height, load_watermark, scaled_dimensions, timed_caption_layers,
+            watermark_offset, CaptionCache, SetUp,
+        },
+        job::Job,
+        DepTy, Outcome, Tools,
+    },
 };
 
-use super::{appdata_init, random_name};
+use super::{dump_image, dump_text, process, resolve_output_path, stderr_tail};
+
+/// CRF values tried in order when the output has to fit under `--max-size`.
+const CRF_LADDER: [u32; 6] = [28, 32, 36, 40, 45, 51];
+
+/// Filters turning the video into a GIF with a palette generated from the video itself.
+const TO_GIF_FILTER: &str = "split[s0][s1];[s0]palettegen[p];[s1][p]paletteuse";
+
+/// Filters making looping video output playable everywhere:
+/// H.264 needs even dimensions and most players only support yuv420p.
+const LOOP_VIDEO_FILTER: &str = "pad=ceil(iw/2)*2:ceil(ih/2)*2,format=yuv420p";
+
+/// Filters uploading the frames to the GPU for the VA-API encoder.
+const VAAPI_UPLOAD_FILTER: &str = "format=nv12,hwupload";
+
+/// Render node the VA-API encoder runs on.
+const VAAPI_DEVICE: &str = "/dev/dri/renderD128";
 
 /// [`FFmpeg`] contains the path to the [`FFmpeg`](https://www.ffmpeg.org/) program.
 pub struct FFmpeg {
     exe: PathBuf,
     input: PathBuf,
+    tools: Tools,
 }
 
 impl FFmpeg {
     /// Returns [`FFmpeg`] that you can operate on.
     ///
     /// # Errors
-    /// Returns an error if [`utils::appdata()`] or [`env::var()`] fail.
-    ///
-    /// [`utils::appdata()`]: crate::utils
-    pub fn init(input: PathBuf) -> Result<Self> {
-        let exe = appdata_init(DepTy::Ffmpeg)?;
-        Ok(Self { exe, input })
+    /// Returns an error if [`Tools::exe()`] fails.
+    pub fn init(input: PathBuf, tools: &Tools) -> Result<Self> {
+        let exe = tools.exe(DepTy::Ffmpeg)?;
+        Ok(Self {
+            exe,
+            input,
+            tools: tools.clone(),
+        })
     }
 
     /// Returns the width and height of the video.
     ///
+    /// Reads them from the first frame of the video.
+    /// See also: [`FFmpeg::first_frame()`]
+    fn dimensions(&mut self) -> Result<(u32, u32)> {
+        let dimensions = self
+            .first_frame()
+            .context(format!(
+                "could not read media dimensions of {}",
+                self.input.display()
+            ))?
+            .dimensions();
+        check_media_dimensions(&self.input, dimensions)?;
+        Ok(dimensions)
+    }
+
+    /// Returns the first frame of the video, as it is stored.
+    ///
     /// Runs `FFmpeg` and saves the first frame of the video.
-    /// Which is later used to get dimensions from [`dimensions()`]
+    /// The frame is removed once it was read.
     ///
-    /// [`dimensions()`]: image::GenericImageView::dimensions()
-    fn dimensions(&mut self) -> Result<(u32, u32)> {
-        let temp_dir = env::temp_dir();
-        let mut name = random_name();
-        name.push_str(".jpg");
-        let file = temp_dir.join(name);
+    /// # Errors
+    /// Returns an error if `FFmpeg` cannot extract the frame.
+    pub fn first_frame(&self) -> Result<ImageBuffer<Rgba<u8>, Vec<u8>>> {
+        let frame = self.tools.temp_file(".jpg");
+        let file = frame.path();
         let file_str = file
             .to_str()
             .context(format!("failed to convert path to str: {}", file.display()))?;
@@ -60,15 +105,234 @@ impl FFmpeg {
             "-y", "-ss", "0.1", "-i", input,
             "-vframes", "1", "-f", "image2", file_str,
         ];
-        let mut command = Command::new(&self.exe);
+        let result = process::output(Command::new(&self.exe).args(args), self.tools.timeout)?;
+        if !result.status.success() {
+            return Err(ErrorKind::FfmpegFailed {
+                status: result.status,
+                stderr: stderr_tail(&result.stderr),
+            })
+            .context("failed to read the first frame of the video");
+        }
+        Ok(image::open(file)?.to_rgba8())
+    }
+
+    /// Returns what `FFmpeg` prints about the input.
+    ///
+    /// `FFmpeg` prints the streams of its input before complaining about
+    /// the missing output, so no separate probing tool is needed.
+    fn input_info(&self) -> Result<String> {
+        let probe = process::output(
+            Command::new(&self.exe)
+                .args(["-hide_banner", "-i"])
+                .arg(&self.input),
+            self.tools.timeout,
+        )?;
+        Ok(String::from_utf8_lossy(&probe.stderr).into_owned())
+    }
+
+    /// Returns the description of the input's video stream.
+    ///
+    /// # Option
+    /// Returns [`None`] if the input has no video stream.
+    fn video_stream(&self) -> Result<Option<String>> {
+        Ok(self
+            .input_info()?
+            .lines()
+            .find(|line| line.contains("Video:"))
+            .map(str::to_owned))
+    }
+
+    /// Returns the duration of the input in seconds.
+    ///
+    /// # Errors
+    /// Returns an error if `FFmpeg` fails to start.
+    ///
+    /// # Option
+    /// Returns [`None`] if the duration of the input is unknown.
+    pub fn duration(&self) -> Result<Option<f64>> {
+        // the duration is printed as `Duration: HH:MM:SS.ss, start: ...`.
+        Ok(self.input_info()?.lines().find_map(|line| {
+            let (_, rest) = line.split_once("Duration: ")?;
+            let (time, _) = rest.split_once(',').unwrap_or((rest, ""));
+            time.trim().split(':').try_fold(0.0, |total, part| {
+                Some(total * 60.0 + part.parse::<f64>().ok()?)
+            })
+        }))
+    }
+
+    /// Returns the width and height the video is displayed at.
+    ///
+    /// Videos with non-square pixels, like anamorphic DVD rips, are stored
+    /// narrower or wider than they are shown. The display width is rounded
+    /// down to an even number.
+    ///
+    /// # Errors
+    /// Returns an error if `FFmpeg` cannot read the video.
+    pub fn display_dimensions(&mut self) -> Result<(u32, u32)> {
+        let (width, height) = self.dimensions()?;
+        Ok(match self.sample_aspect_ratio()? {
+            Some((num, den)) => {
+                let width = (u64::from(width) * u64::from(num) / u64::from(den)) as u32;
+                (width - width % 2, height)
+            }
+            None => (width, height),
+        })
+    }
+
+    /// Detects the sample (pixel) aspect ratio of the input's video stream.
+    ///
+    /// # Option
+    /// Returns [`None`] if the pixels are square or the ratio is not stated.
+    fn sample_aspect_ratio(&self) -> Result<Option<(u32, u32)>> {
+        let stream = match self.video_stream()? {
+            Some(stream) => stream,
+            None => return Ok(None),
+        };
+        // the stream shows up as `720x480 [SAR 32:27 DAR 16:9]`.
+        let ratio: Option<(u32, u32)> = stream
+            .split("[SAR ")
+            .nth(1)
+            .and_then(|rest| rest.split_whitespace().next())
+            .and_then(|ratio| ratio.split_once(':'))
+            .and_then(|(num, den)| Some((num.parse().ok()?, den.parse().ok()?)));
+        Ok(ratio.filter(|&(num, den)| num != 0 && den != 0 && num != den))
+    }
+
+    /// Detects the color range of the input's video stream.
+    ///
+    /// # Option
+    /// Returns [`None`] if the input does not state its range.
+    fn source_color_range(&self) -> Result<Option<ColorRange>> {
+        let stream = match self.video_stream()? {
+            Some(stream) => stream,
+            None => return Ok(None),
+        };
+        // full range streams show up as `yuvj420p` or `yuv420p(pc, ...)`.
+        if stream.contains("yuvj") || stream.contains("(pc") {
+            Ok(Some(ColorRange::Pc))
+        } else if stream.contains("(tv") {
+            Ok(Some(ColorRange::Tv))
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// Returns the filter converting to the requested color range and
+    /// the arguments tagging the output with its range.
+    ///
+    /// Without `--color-range` the output is only tagged with the range of the input.
+    fn color_range_args(&self, job: &Job) -> Result<(Option<String>, Vec<String>)> {
+        let (filter, range) = match job.color_range() {
+            Some(range) => (
+                Some(format!("scale=out_range={}", range.as_str())),
+                Some(range),
+            ),
+            None => (None, self.source_color_range()?),
+        };
+        let args = range.map_or_else(Vec::new, |range| {
+            vec!["-color_range".into(), range.as_str().into()]
+        });
+        Ok((filter, args))
+    }
+
+    /// Runs `FFmpeg` with the given arguments, writing to `output`.
+    ///
+    /// Waits for `FFmpeg` to exit. Fails with [`FfmpegFailed`] if it exits
+    /// with an error, or with [`NoOutputWritten`] if `output` is missing
+    /// or empty afterwards.
+    ///
+    /// [`FfmpegFailed`]: crate::error::ErrorKind::FfmpegFailed
+    /// [`NoOutputWritten`]: crate::error::ErrorKind::NoOutputWritten
+    fn encode(&self, args: &[String], output: &Path) -> Result<()> {
+        // #[cfg(windows)]
+        // let command = command.creation_flags(0x00000080);
+        let result = process::output(
+            Command::new(&self.exe).args(args).arg(output),
+            self.tools.timeout,
+        )?;
+        let stderr = String::from_utf8_lossy(&result.stderr);
+        if !result.status.success() {
+            return Err(ErrorKind::FfmpegFailed {
+                status: result.status,
+                stderr: stderr_tail(&result.stderr),
+            }
+            .into());
+        }
+        if !stderr.trim().is_empty() {
+            warn!("ffmpeg: {}", stderr.trim());
+        }
+        if fs::metadata(output).map_or(true, |meta| meta.len() == 0) {
+            return Err(ErrorKind::NoOutputWritten {
+                path: output.display().to_string(),
+                stderr: stderr_tail(&result.stderr),
+            }
+            .into());
+        }
+        Ok(())
+    }
+
+    /// Converts the GIF input to an animated WebP at `output`.
+    ///
+    /// `loops` is the number of times the WebP repeats, 0 repeats it forever.
+    ///
+    /// # Errors
+    /// Returns an error if `FFmpeg` fails to convert the GIF.
+    pub fn gif_to_webp(&self, output: &Path, loops: u16) -> Result<()> {
+        let input = self
+            .input
+            .to_str()
+            .context(format!(
+                "failed to convert input arg to str: {}",
+                self.input.display()
+            ))?
+            .to_string();
+        let mut args = vec![
+            "-hide_banner".into(),
+            "-loglevel".into(),
+            "error".into(),
+            "-y".into(),
+            "-i".into(),
+            input,
+        ];
+        args.extend(["-an".into(), "-loop".into(), loops.to_string()]);
+        self.encode(&args, output)
+    }
 
+    /// Re-encodes the output with increasing CRF values until it is at most `max_size` bytes.
+    ///
+    /// Only CRF values above the one given with `--crf` are tried.
+    /// Gives up with a warning once every CRF value was tried.
+    fn shrink(&self, args: &[String], output: &Path, max_size: u64, job: &Job) -> Result<()> {
+        let mut size = fs::metadata(output)?.len();
+        if size <= max_size {
+            return Ok(());
+        }
+        let max = human_bytes(max_size as f64);
+        let start = job.crf().unwrap_or(0);
+        for crf in CRF_LADDER.into_iter().filter(|&crf| crf > start) {
+            info!(
+                "Output is {}, which is over {max}. Retrying with CRF {crf}...",
+                human_bytes(size as f64)
+            );
+            let mut retry = args.to_vec();
+            retry.extend([quality_flag(job).into(), crf.to_string()]);
+            self.encode(&retry, output)?;
 
-        command
-            .args(&args)
-            .spawn()
-            .context("failed to start ffmpeg")?
-            .wait()?;
-        Ok(image::open(file)?.dimensions())
+            size = fs::metadata(output)?.len();
+            if size <= max_size {
+                info!(
+                    "{} Final settings: CRF {crf} ({})",
+                    Paint::green("Output fits."),
+                    human_bytes(size as f64)
+                );
+                return Ok(());
+            }
+        }
+        warn!(
+            "Could not shrink the output under {max}, it is {}.",
+            human_bytes(size as f64)
+        );
+        Ok(())
     }
 
     /// Runs the main logic of video processing.
@@ -76,102 +340,428 @@ impl FFmpeg {
     /// `FFmpeg` arguments used:
     ///
     /// ```text
-    /// ffmpeg.exe -i media.mp4 -i caption.jpg \
+    /// ffmpeg.exe -i media.mp4 -i caption.png \
     /// -filter_complex \
-    /// "[0:v]pad=640:video_width:0:(video_height + caption_height)[a]; \
+    /// "[0:v]scale=video_width:video_height,setsar=1,pad=640:video_width:0:(video_height + caption_height)[a]; \
     /// [a][1:v]overlay=0:0,setsar=1" \
     /// -c:a copy output.mp4
     /// ```
     #[allow(clippy::missing_errors_doc)]
     pub fn process_media(
         &mut self,
-        font: Font<'static>,
-        text: &str,
-        out_path: &Path,
-        name: &str,
-        overwrite: bool,
-    ) -> Result<()> {
-        let (width, height) = self.dimensions()?;
-        let init = SetUp::init(font).with_dimensions(width, height);
-        info!("Creating caption image...");
-
-        let image = TextImage::new(init, text).render()?;
-        let mut caption_name = random_name();
-        caption_name.push_str(".jpg");
-        let caption_location = std::env::temp_dir().join(caption_name);
-        image.save(&caption_location)?;
-        info!("{}", Paint::green("Caption image created!"));
-
-        let caption_height = image.dimensions().1;
-        let (video_width, video_height) = self.dimensions()?;
-
-        // ffmpeg.exe -i .\cat.mp4 -i .\caption.jpg \
-        // -filter_complex "[0:v]pad=640:788:0:148[a];[a][1:v]overlay=0:0,setsar=1"
-        // -c:a copy output.mp4
-        let mut base_args = vec!["-hide_banner", "-loglevel", "error"];
-
-        let input_args = [
-            "-i",
-            self.input.to_str().context(format!(
+        fonts: Vec<Font<'static>>,
+        job: &Job,
+        cache: &mut CaptionCache,
+    ) -> Result<Outcome> {
+        let media_type = job.media_type();
+        let (out_path, name, overwrite) = (job.output(), job.name(), job.overwrites());
+        let output = resolve_output_path(out_path, name, overwrite);
+        let input = self
+            .input
+            .to_str()
+            .context(format!(
                 "failed to convert input arg to str: {}",
                 self.input.display()
-            ))?,
-            "-i",
-            caption_location.to_str().context(format!(
-                "failed to convert input arg to str: {}",
-                caption_location.display()
-            ))?,
-        ];
-        let filter_complex = [
-            "-filter_complex".into(),
-            format!(
-                "[0:v]pad={video_width}:{}:0:{caption_height}[a];[a][1:v]overlay=0:0,setsar=1",
-                video_height + caption_height,
-            ),
-        ];
+            ))?
+            .to_string();
+        let events = job.events();
 
-        let output = if out_path.join(name).exists() {
-            if overwrite {
-                info!("Overwrite is enabled. Any file with the same name ({}) will be overwritten by the output file.", name);
-                base_args.push("-y");
-                out_path.join(name)
-            } else {
-                warn!("Overwrite is disabled. File with similar name found. Modifying name.");
-                out_path.join(format!("{}-{}", random_name(), name))
+        if job.no_caption() {
+            // the output path was resolved above, so it is always safe to overwrite.
+            let (range_filter, range_args) = self.color_range_args(job)?;
+            let filters: Vec<_> = scale_filter(job)
+                .into_iter()
+                .chain(range_filter)
+                .chain(job.loop_video().map(|_| LOOP_VIDEO_FILTER.to_string()))
+                .chain(job.to_gif().then(|| TO_GIF_FILTER.to_string()))
+                .chain(upload_filter(job).map(ToString::to_string))
+                .collect();
+            let mut args = input_args(job, input);
+            if !filters.is_empty() {
+                args.extend(["-vf".into(), filters.join(",")]);
             }
+            args.extend(range_args);
+            args.extend(output_args(job));
+            info!("{}", Paint::blue("Transcoding Media..."));
+            events.stage_started("render");
+            self.encode(&args, &output)
+                .map_err(|err| hwaccel_hint(err, job))?;
+            let optimized = if job.to_gif() {
+                finish_gif(job, &output)?
+            } else {
+                if let Some(max_size) = job.max_size() {
+                    self.shrink(&args, &output, max_size, job)?;
+                }
+                false
+            };
+            events.stage_finished("render");
+            events.file_written(&output);
+            let dimensions = self.display_dimensions()?;
+            return Ok(Outcome {
+                output,
+                media_type,
+                dimensions: job
+                    .scale_output()
+                    .map_or(dimensions, |width| scaled_dimensions(dimensions, width)),
+                caption_lines: 0,
+                optimized,
+            });
+        }
+
+        // every call runs ffmpeg, so the dimensions are only read once.
+        let (video_width, video_height) = self.display_dimensions()?;
+        let init = SetUp::from_job(fonts, job, video_width, video_height)?;
+        let segments = job.caption_segments();
+        let caption_lines = caption_lines(job, &init, segments);
+        info!("Creating caption image...");
+        events.stage_started("caption");
+
+        // ffmpeg needs an even output height and the captions are already even.
+        let gap = job.gap() + job.gap() % 2;
+        let mut layers = timed_caption_layers(job, &init, cache, gap, segments)?;
+        let over = job.overlay_mode() == OverlayMode::Over;
+        // a caption drawn over the video neither pads nor moves it.
+        let (top_height, caption_height) = if over {
+            (0, 0)
         } else {
-            out_path.join(name)
+            let (top_height, bottom_height) = layers_height(&layers);
+            (top_height, top_height + bottom_height)
         };
+        for (strip, _, _) in &mut layers {
+            fade(strip, job.overlay_opacity());
+        }
+        check_output_height(video_height + caption_height)?;
+        for (i, (strip, _, window)) in layers.iter().enumerate() {
+            let name = match (i, window) {
+                (0, _) => "caption.png".to_string(),
+                (_, Some(_)) => format!("caption-{i}.png"),
+                (_, None) => "caption-bottom.png".to_string(),
+            };
+            dump_image(job.debug_dump(), &name, strip)?;
+        }
+        info!("{}", Paint::green("Caption image created!"));
+        events.stage_finished("caption");
 
-        let end_args = [
-            "-c:a",
-            "copy",
-            output.to_str().context(format!(
-                "failed to convert output arg to str: {}",
-                output.display()
-            ))?,
-        ];
-
-        let mut command = Command::new(&self.exe);
+        // ffmpeg.exe -i .\cat.mp4 -i .\caption.png \
+        // -filter_complex "[0:v]pad=640:788:0:148[v1];[v1][1:v]overlay=0:0,setsar=1"
+        // -c:a copy output.mp4
+        // the output path was resolved above, so it is always safe to overwrite.
+        let feather = job.feather().min(video_height);
+        let mut filter = format!(
+            "[0:v]scale={video_width}:{video_height},setsar=1,pad={video_width}:{}:0:{top_height}",
+            video_height + caption_height,
+        );
+        // Removed on drop, so the images do not outlive a failed encode either.
+        let mut overlays = Vec::new();
+        for (strip, position, window) in &layers {
+            let caption = self.tools.temp_file(".png");
+            strip.save(caption.path())?;
+            let (caption_y, fringe_y) = match position {
+                CaptionPosition::Top if over => (0, strip.height()),
+                CaptionPosition::Top => (0, top_height),
+                CaptionPosition::Bottom => {
+                    let y = if over {
+                        video_height.saturating_sub(strip.height())
+                    } else {
+                        top_height + video_height
+                    };
+                    (y, y.saturating_sub(feather))
+                }
+            };
+            overlays.push((caption, caption_y, *window));
+            if feather > 0 {
+                let fringe = self.tools.temp_file(".png");
+                feather_fringe(strip, feather, *position).save(fringe.path())?;
+                overlays.push((fringe, fringe_y, *window));
+            }
+        }
+        for (i, (_, y, window)) in overlays.iter().enumerate() {
+            let input = i + 1;
+            filter = format!("{filter}[v{input}];[v{input}][{input}:v]overlay=0:{y}");
+            if let Some((start, end)) = window {
+                filter = format!("{filter}:enable='between(t,{start},{end})'");
+            }
+        }
+        let watermark = match job.watermark() {
+            Some(path) => {
+                let mark = load_watermark(path, video_width, job.watermark_opacity())?;
+                let (x, y) = watermark_offset(
+                    (video_width, video_height + caption_height),
+                    mark.dimensions(),
+                    job.watermark_corner(),
+                );
+                let input = overlays.len() + 1;
+                filter = format!("{filter}[v{input}];[v{input}][{input}:v]overlay={x}:{y}");
+                let image = self.tools.temp_file(".png");
+                mark.save(image.path())?;
+                Some(image)
+            }
+            None => None,
+        };
+        filter = format!("{filter},setsar=1");
+        if let Some(scale) = scale_filter(job) {
+            filter = format!("{filter},{scale}");
+        }
+        let (range_filter, range_args) = self.color_range_args(job)?;
+        if let Some(range_filter) = range_filter {
+            filter = format!("{filter},{range_filter}");
+        }
+        if job.loop_video().is_some() {
+            filter = format!("{filter},{LOOP_VIDEO_FILTER}");
+        }
+        if job.to_gif() {
+            filter = format!("{filter},{TO_GIF_FILTER}");
+        }
+        if let Some(upload) = upload_filter(job) {
+            filter = format!("{filter},{upload}");
+        }
+        let mut args = input_args(job, input);
+        for image in overlays.iter().map(|(image, _, _)| image).chain(&watermark) {
+            args.extend(["-i".into(), path_arg(image.path())?]);
+        }
+        args.extend(["-filter_complex".into(), filter]);
+        args.extend(range_args);
+        args.extend(output_args(job));
 
-        // #[cfg(windows)]
-        // let command = command.creation_flags(0x00000080);
+        let command: Vec<_> = std::iter::once(self.exe.display().to_string())
+            .chain(args.iter().cloned())
+            .chain(std::iter::once(output.display().to_string()))
+            .map(|arg| format!("{arg:?}"))
+            .collect();
+        dump_text(job.debug_dump(), "ffmpeg.txt", &command.join(" "))?;
 
         info!("{}", Paint::blue("Rendering Media..."));
-        command
-            .args(base_args)
-            .args(input_args)
-            .args(filter_complex)
-            .args(end_args)
-            .spawn()?
-            .wait()
-            .context("ffmpeg failed to start.")?;
+        events.stage_started("render");
+        self.encode(&args, &output)
+            .map_err(|err| hwaccel_hint(err, job))?;
+        let optimized = if job.to_gif() {
+            finish_gif(job, &output)?
+        } else {
+            if let Some(max_size) = job.max_size() {
+                self.shrink(&args, &output, max_size, job)?;
+            }
+            false
+        };
+        events.stage_finished("render");
+        events.file_written(&output);
 
         info!(
             "{} {name} at {}",
             Paint::green("Created"),
-            out_path.to_str().context("output path is not utf-8")?,
+            out_path.display(),
         );
-        Ok(())
+        let dimensions = (video_width, video_height + caption_height);
+        Ok(Outcome {
+            output,
+            media_type,
+            dimensions: job
+                .scale_output()
+                .map_or(dimensions, |width| scaled_dimensions(dimensions, width)),
+            caption_lines,
+            optimized,
+        })
+    }
+}
+
+/// Converts the path of an `FFmpeg` input to an argument.
+///
+/// # Errors
+/// Returns an error if the path is not valid utf-8.
+fn path_arg(path: &Path) -> Result<String> {
+    path.to_str().map(str::to_owned).context(format!(
+        "failed to convert input arg to str: {}",
+        path.display()
+    ))
+}
+
+/// Returns the `FFmpeg` arguments reading the input media.
+///
+/// The input is repeated when a looping video was requested.
+fn input_args(job: &Job, input: String) -> Vec<String> {
+    let mut args: Vec<String> = vec![
+        "-hide_banner".into(),
+        "-loglevel".into(),
+        "error".into(),
+        "-y".into(),
+    ];
+    if upload_filter(job).is_some() {
+        args.extend(["-vaapi_device".into(), VAAPI_DEVICE.into()]);
+    }
+    if let Some(loops) = job.loop_video() {
+        args.extend(["-stream_loop".into(), loops.saturating_sub(1).to_string()]);
+    }
+    args.extend(["-i".into(), input]);
+    args
+}
+
+/// Returns the `FFmpeg` arguments for the encoder, audio and container of the output.
+///
+/// Looping videos are muted and have their index at the start of
+/// the file, so they start playing before they are fully loaded.
+/// Other videos copy their audio unless it is removed or re-encoded.
+fn output_args(job: &Job) -> Vec<String> {
+    if job.to_gif() {
+        // GIFs have no audio and the video encoder options do not apply to them.
+        let mut args: Vec<String> = vec!["-an".into()];
+        if let Some(threads) = job.encoder_threads() {
+            args.extend(["-threads".into(), threads.to_string()]);
+        }
+        return args;
+    }
+    let mut args: Vec<String> = if job.loop_video().is_some() {
+        vec!["-an".into(), "-movflags".into(), "+faststart".into()]
+    } else {
+        match (job.no_audio(), job.audio_codec()) {
+            (true, _) => vec!["-an".into()],
+            (false, Some(codec)) => vec!["-c:a".into(), codec.into()],
+            (false, None) => vec!["-c:a".into(), "copy".into()],
+        }
+    };
+    if let Some(hwaccel) = job.hwaccel() {
+        args.extend(["-c:v".into(), hwaccel.encoder().into()]);
+    }
+    if let Some(crf) = job.crf() {
+        args.extend([quality_flag(job).into(), crf.to_string()]);
+    }
+    // hardware encoders name their presets differently, if they have any.
+    if let Some(preset) = job.preset().filter(|_| job.hwaccel().is_none()) {
+        args.extend(["-preset".into(), preset.as_str().into()]);
+    }
+    if let Some(threads) = job.encoder_threads() {
+        args.extend(["-threads".into(), threads.to_string()]);
+    }
+    args
+}
+
+/// Returns the filter scaling the output to `--scale-output`.
+///
+/// # Option
+/// Returns [`None`] if the output keeps the width of the media.
+fn scale_filter(job: &Job) -> Option<String> {
+    job.scale_output().map(|width| format!("scale={width}:-2"))
+}
+
+/// Returns the `FFmpeg` option setting the constant quality of the output encoder.
+fn quality_flag(job: &Job) -> &'static str {
+    job.hwaccel().map_or("-crf", HwAccel::quality_flag)
+}
+
+/// Returns the filter uploading frames for the VA-API encoder.
+///
+/// # Option
+/// Returns [`None`] unless a video is encoded with VA-API.
+fn upload_filter(job: &Job) -> Option<&'static str> {
+    if job.hwaccel() == Some(HwAccel::Vaapi) && !job.to_gif() {
+        Some(VAAPI_UPLOAD_FILTER)
+    } else {
+        None
+    }
+}
+
+/// Explains a failed hardware accelerated encode.
+///
+/// `FFmpeg` exits with an error when the accelerator is not available,
+/// so the error suggests encoding in software instead.
+fn hwaccel_hint(err: anyhow::Error, job: &Job) -> anyhow::Error {
+    match job.hwaccel() {
+        Some(hwaccel) if matches!(err.downcast_ref(), Some(ErrorKind::FfmpegFailed { .. })) => err
+            .context(format!(
+                "encoding with {} failed, the accelerator may not be available on this machine (run again without --hwaccel to encode in software)",
+                hwaccel.encoder()
+            )),
+        _ => err,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Returns an [`FFmpeg`] running `exe` on `input`.
+    #[cfg(unix)]
+    fn ffmpeg(exe: &Path, input: &Path) -> FFmpeg {
+        FFmpeg {
+            exe: exe.to_path_buf(),
+            input: input.to_path_buf(),
+            tools: Tools::default(),
+        }
+    }
+
+    /// Writes a shell script standing in for `FFmpeg` that runs `body` and writes nothing.
+    #[cfg(unix)]
+    fn fake_ffmpeg(script: &Path, body: &str) {
+        use std::os::unix::fs::PermissionsExt;
+        fs::write(script, format!("#!/bin/sh\n{body}\n")).unwrap();
+        fs::set_permissions(script, fs::Permissions::from_mode(0o755)).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn encode_fails_if_ffmpeg_succeeds_without_output() {
+        let tools = Tools::default();
+        let (script, output) = (tools.temp_file(".sh"), tools.temp_file(".mp4"));
+        fake_ffmpeg(
+            script.path(),
+            "echo 'Output file is empty, nothing was encoded' >&2",
+        );
+        let err = ffmpeg(script.path(), Path::new("in.mp4"))
+            .encode(&[], output.path())
+            .unwrap_err();
+        match err.downcast_ref() {
+            Some(ErrorKind::NoOutputWritten { stderr, .. }) => {
+                assert!(stderr.contains("nothing was encoded"), "{stderr}");
+            }
+            _ => panic!("expected NoOutputWritten, got {err}"),
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn encode_fails_if_ffmpeg_exits_with_an_error() {
+        let tools = Tools::default();
+        let (script, output) = (tools.temp_file(".sh"), tools.temp_file(".mp4"));
+        fake_ffmpeg(script.path(), "echo 'No such filter' >&2; exit 1");
+        let err = ffmpeg(script.path(), Path::new("in.mp4"))
+            .encode(&[], output.path())
+            .unwrap_err();
+        assert!(
+            matches!(err.downcast_ref(), Some(ErrorKind::FfmpegFailed { .. })),
+            "expected FfmpegFailed, got {err}"
+        );
+    }
+
+    // needs FFmpeg on the path, it is skipped without one.
+    #[cfg(unix)]
+    #[test]
+    fn encode_fails_on_a_broken_filter() {
+        let exe = match which::which("ffmpeg") {
+            Ok(exe) => exe,
+            Err(_) => {
+                eprintln!("ffmpeg not found, skipping");
+                return;
+            }
+        };
+        let tools = Tools::default();
+        let (input, output) = (tools.temp_file(".gif"), tools.temp_file(".mp4"));
+        ImageBuffer::from_pixel(16, 16, Rgba([255u8, 0, 0, 255]))
+            .save(input.path())
+            .unwrap();
+        let args: Vec<String> = ["-hide_banner", "-loglevel", "error", "-y", "-i"]
+            .iter()
+            .map(ToString::to_string)
+            .chain([input.path().display().to_string()])
+            .chain(["-vf".to_string(), "nosuchfilter=1".to_string()])
+            .collect();
+        let err = ffmpeg(&exe, input.path())
+            .encode(&args, output.path())
+            .unwrap_err();
+        assert!(
+            matches!(
+                err.downcast_ref(),
+                Some(ErrorKind::FfmpegFailed { .. } | ErrorKind::NoOutputWritten { .. })
+            ),
+            "{err}"
+        );
+        assert!(fs::metadata(output.path()).map_or(true, |meta| meta.len() == 0));
     }
 }